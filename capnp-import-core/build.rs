@@ -0,0 +1,461 @@
+use anyhow::{anyhow, bail, Context};
+use relative_path::RelativePathBuf;
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// update this whenever you change the subtree pointer
+const CAPNP_VERSION: &str = "0.11.0";
+
+enum CapnprotoAcquired {
+    /// Freshly built from the vendored sources, relative to `OUT_DIR`.
+    Locally(relative_path::RelativePathBuf),
+    /// Built from the vendored sources by an earlier run of this build script, relative to `OUT_DIR`.
+    Cached(relative_path::RelativePathBuf),
+    OnSystem(PathBuf),
+}
+
+impl CapnprotoAcquired {
+    // how the binary was obtained, as reported by capnp_source()
+    fn source(&self, binary: &Path) -> String {
+        match self {
+            CapnprotoAcquired::Locally(_) => {
+                format!("built from the vendored sources: {}", binary.display())
+            }
+            CapnprotoAcquired::Cached(_) => format!(
+                "reused an earlier build of the vendored sources: {}",
+                binary.display()
+            ),
+            CapnprotoAcquired::OnSystem(_) => format!("system: {}", binary.display()),
+        }
+    }
+}
+
+impl Display for CapnprotoAcquired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapnprotoAcquired::Locally(e) | CapnprotoAcquired::Cached(e) => write!(f, "{}", e),
+            CapnprotoAcquired::OnSystem(e) => write!(f, "{}", e.display()),
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    // we're making the assumption that the executable is always accessible.
+    // if we can't make this assumption, we can just include_bytes!() it and then unpack it at runtime.
+
+    println!("cargo:rerun-if-changed={}", capnproto_dir().display());
+    println!("cargo:rerun-if-env-changed=CAPNP_IMPORT_SOURCE_DIR");
+    println!("cargo:rerun-if-env-changed=CAPNP_IMPORT_DENY_BUILD");
+    println!("cargo:rerun-if-env-changed=CAPNP_IMPORT_BUILD_TYPE");
+    println!("cargo:rerun-if-env-changed=CAPNP_IMPORT_BUILD_JOBS");
+
+    let out_dir = PathBuf::from(
+        env::var("OUT_DIR").context("Cargo did not set $OUT_DIR. this should be impossible.")?,
+    );
+
+    // docs.rs can neither build nor fetch capnp, and documentation only needs the module structure
+    if cfg!(feature = "docs-only") {
+        fs::write(
+            out_dir.join("extract_bin.rs"),
+            format!(
+                "
+#[allow(dead_code)]
+fn commandhandle() -> anyhow::Result<tempfile::TempDir> {{
+    Ok(tempfile::tempdir()?)
+}}
+
+#[allow(dead_code)]
+fn scratchdir() -> anyhow::Result<tempfile::TempDir> {{
+    Ok(tempfile::tempdir()?)
+}}
+
+#[allow(dead_code)]
+const CAPNP_EXECUTABLE: &[u8] = &[];
+
+#[allow(dead_code)]
+fn write_executable(_: &std::path::Path) -> anyhow::Result<()> {{
+    Ok(())
+}}
+{}",
+                constants(
+                    None,
+                    CAPNP_VERSION,
+                    None,
+                    false,
+                    "none, built with docs-only",
+                    "none, built with docs-only",
+                    "none, docs-only doesn't embed capnp",
+                ),
+            ),
+        )?;
+        return Ok(());
+    }
+
+    // updated with the final path of the capnp binary if it's ever found, to be recorded
+    // and consumed by capnp_import!()
+    let mut capnp_path: Option<CapnprotoAcquired> = None;
+    // whatever capnp was found on the system, and the version it reported, for diagnose()
+    let mut system_capnp: Option<(PathBuf, Option<String>)> = None;
+
+    // only build if it can't be detected in the $PATH
+    // check if there is a capnp binary in the path that meets the version requirement
+    let existing_capnp: anyhow::Result<PathBuf> = (|| {
+        let bin = which::which("capnp").context("could not find a system capnp binary")?;
+        system_capnp = Some((bin.clone(), None));
+        let version = get_version(&bin).context(
+            "could not obtain version of found binary, system capnp may be inaccessible",
+        )?;
+        system_capnp = Some((bin.clone(), Some(version.trim().to_owned())));
+
+        println!("found capnp '{version}'");
+
+        if version.trim() == format!("Cap'n Proto version {}", CAPNP_VERSION) {
+            capnp_path = Some(CapnprotoAcquired::OnSystem(bin.clone()));
+            Ok(bin)
+        } else {
+            println!("cargo:warning=System version of capnp found ({}) does not meet version requirement {CAPNP_VERSION}.", &version);
+            Err(anyhow!(
+                "version of system capnp does not meet version requirements"
+            ))?
+        }
+    })();
+
+    // no capnp here, proceed to build
+    if let Err(e) = existing_capnp {
+        // the build only uses the vendored sources, so deny-net-fetch doesn't prevent it
+        if cfg!(feature = "deny-build") || env::var_os("CAPNP_IMPORT_DENY_BUILD").is_some() {
+            bail!(
+                "Couldn't find a compatible system capnp ({CAPNP_VERSION}): {}\n refusing to build it from source because deny-build is set",
+                e
+            );
+        }
+
+        println!("Couldn't find a local capnp: {}", e);
+        println!("building...");
+
+        // when capnproto accepts our PR, windows can fetch bin artifacts from it.
+        // until then, we must build capnproto ourselves.
+
+        let built_bin = build_with_cmake(&out_dir)?;
+
+        capnp_path = Some(built_bin);
+    }
+
+    let capnp_path = capnp_path.unwrap();
+    let binary = match &capnp_path {
+        CapnprotoAcquired::Locally(path) | CapnprotoAcquired::Cached(path) => {
+            path.to_path(&out_dir)
+        }
+        CapnprotoAcquired::OnSystem(path) => path.clone(),
+    };
+    let source = capnp_path.source(&binary);
+    println!("capnp acquired: {source}");
+    // a cross compiled binary can't be asked, but then it was built from the subtree
+    let version = get_version(&binary)
+        .ok()
+        .and_then(|version| {
+            Some(
+                version
+                    .trim()
+                    .strip_prefix("Cap'n Proto version ")?
+                    .to_owned(),
+            )
+        })
+        .unwrap_or_else(|| CAPNP_VERSION.to_owned());
+    // two builds of the same version can differ, the hash tells whether two machines embedded the same one
+    let binary_hash: String = Sha256::digest(fs::read(&binary)?)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let include_dir = find_include_dir(&capnp_path, &out_dir);
+    if include_dir.is_none() {
+        println!("cargo:warning=Could not find the standard capnp schemas, imports of /capnp/*.capnp will fail.");
+    }
+
+    fs::write(
+        out_dir.join("extract_bin.rs"),
+        format!(
+            "
+#[allow(dead_code)]
+fn commandhandle() -> anyhow::Result<tempfile::TempDir> {{
+    let tempdir = scratchdir()?;
+    write_executable(&tempdir.path().join(\"capnp\"))?;
+    Ok(tempdir)
+}}
+
+// a fresh temporary directory, for the extracted binary and whatever else capnp needs around it
+#[allow(dead_code)]
+fn scratchdir() -> anyhow::Result<tempfile::TempDir> {{
+    // the system temp dir may not allow executing files, so it can be overridden
+    Ok(match std::env::var_os(\"CAPNP_IMPORT_TMPDIR\") {{
+        Some(dir) => tempfile::Builder::new().prefix(\"capnp-import\").tempdir_in(&dir).map_err(|e| {{
+            anyhow::anyhow!(\"could not create a temporary directory in $CAPNP_IMPORT_TMPDIR ({{:?}}): {{}}\", dir, e)
+        }})?,
+        None => tempfile::tempdir()?,
+    }})
+}}
+
+#[allow(dead_code)]
+const CAPNP_EXECUTABLE: &[u8] = include_bytes!({:?});
+
+// writes the embedded binary to `path`, executable
+#[allow(dead_code)]
+fn write_executable(path: &std::path::Path) -> anyhow::Result<()> {{
+    use std::io::Write;
+    #[cfg(any(target_os = \"linux\", target_os = \"macos\"))]
+    use std::os::unix::fs::OpenOptionsExt;
+
+    #[cfg(any(target_os = \"linux\", target_os = \"macos\"))]
+    let mut handle = 
+        std::fs::OpenOptions::new()
+        .write(true)
+        .mode(0o770)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+
+    #[cfg(target_os = \"windows\")]
+    let mut handle = std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+
+    #[cfg(not(any(target_os = \"linux\", target_os = \"macos\", target_os = \"windows\")))]
+    compile_error!(\"capnp-import does not support your operating system!\");
+
+    handle.write_all(CAPNP_EXECUTABLE)?;
+
+    Ok(())
+}}
+
+{}",
+            // a literal escaped by Debug holds any path, including spaces and backslashes
+            binary.to_string_lossy(),
+            constants(
+                include_dir.map(|dir| dir.to_string_lossy().replace('\\', "/")),
+                &version,
+                system_capnp
+                    .as_ref()
+                    .map(|(path, version)| (path.to_string_lossy().into_owned(), version.clone())),
+                !matches!(capnp_path, CapnprotoAcquired::OnSystem(_)),
+                &binary.to_string_lossy(),
+                &binary_hash,
+                &source,
+            ),
+        ),
+    )?;
+
+    Ok(())
+}
+
+// everything recorded about the embedded binary, for capnp_import_core to consume
+fn constants(
+    include_dir: Option<String>,
+    version: &str,
+    system_capnp: Option<(String, Option<String>)>,
+    built_from_source: bool,
+    binary: &str,
+    binary_hash: &str,
+    source: &str,
+) -> String {
+    format!(
+        "
+/// The directory containing the standard `capnp/*.capnp` schemas, as an extracted binary can't find it.
+#[allow(dead_code)]
+const CAPNP_INCLUDE_DIR: Option<&str> = {:?};
+
+/// The version reported by the embedded binary.
+#[allow(dead_code)]
+const CAPNP_VERSION: &str = {:?};
+
+/// The version a system capnp has to have to be embedded.
+#[allow(dead_code)]
+const REQUIRED_CAPNP_VERSION: &str = {:?};
+
+/// The capnp found on the system, if any, and the version it reported.
+#[allow(dead_code)]
+const SYSTEM_CAPNP: Option<(&str, Option<&str>)> = {:?};
+
+/// Whether the embedded binary was built from the vendored sources.
+#[allow(dead_code)]
+const BUILT_FROM_SOURCE: bool = {:?};
+
+/// Where the embedded binary was taken from.
+#[allow(dead_code)]
+const CAPNP_BINARY: &str = {:?};
+
+/// The SHA-256 of the embedded binary, in lowercase hex.
+#[allow(dead_code)]
+const CAPNP_BINARY_HASH: &str = {:?};
+
+/// How the embedded binary was obtained, and where from.
+#[allow(dead_code)]
+const CAPNP_SOURCE: &str = {:?};
+",
+        include_dir,
+        version,
+        CAPNP_VERSION,
+        system_capnp,
+        built_from_source,
+        binary,
+        binary_hash,
+        source,
+    )
+}
+
+// the standard schemas are installed next to the binary, fall back to the ones in the subtree
+fn find_include_dir(capnp_path: &CapnprotoAcquired, out_dir: &Path) -> Option<PathBuf> {
+    let installed = match capnp_path {
+        CapnprotoAcquired::Locally(_) | CapnprotoAcquired::Cached(_) => {
+            Some(out_dir.join("include"))
+        }
+        CapnprotoAcquired::OnSystem(bin) => fs::canonicalize(bin)
+            .ok()
+            .and_then(|bin| Some(bin.parent()?.parent()?.join("include"))),
+    };
+    let vendored = fs::canonicalize(capnproto_dir().join("c++/src")).ok();
+
+    installed
+        .into_iter()
+        .chain(vendored)
+        // RPC schemas import rpc.capnp and persistent.capnp, so a partial installation won't do
+        .find(|dir| {
+            ["c++.capnp", "rpc.capnp", "persistent.capnp"]
+                .iter()
+                .all(|schema| dir.join("capnp").join(schema).is_file())
+        })
+}
+
+// the version line capnp prints, wherever it ends up: wrapper scripts can print more lines around it, or
+// redirect it to stderr
+fn get_version(executable: &Path) -> anyhow::Result<String> {
+    let output = Command::new(executable).arg("--version").output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .find_map(|line| {
+            line.find("Cap'n Proto version ")
+                .map(|at| line[at..].trim().to_owned())
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "{} --version printed no version: {}",
+                executable.display(),
+                format!("{}{}", stdout, stderr).trim()
+            )
+        })
+}
+
+// the capnproto sources, the subtree packaged with this crate unless $CAPNP_IMPORT_SOURCE_DIR points elsewhere
+fn capnproto_dir() -> PathBuf {
+    env::var_os("CAPNP_IMPORT_SOURCE_DIR").map_or_else(|| PathBuf::from("capnproto"), PathBuf::from)
+}
+
+// build capnproto with cmake, configured for windows and linux envs
+fn build_with_cmake(out_dir: &PathBuf) -> anyhow::Result<CapnprotoAcquired> {
+    // vendoring the crate without the subtree otherwise fails deep inside cmake, with no hint as to why
+    let source_dir = capnproto_dir();
+    if !source_dir.join("CMakeLists.txt").is_file() {
+        bail!(
+            "the capnproto sources to build capnp {CAPNP_VERSION} from are missing, there is no CMakeLists.txt in {:?}. Restore the capnproto subtree of capnp-import-core, or set CAPNP_IMPORT_SOURCE_DIR to a checkout of capnproto {CAPNP_VERSION}. Installing capnp {CAPNP_VERSION} on the PATH avoids the build altogether",
+            source_dir
+        );
+    }
+
+    // is dst consistent? might need to write this down somewhere if it isn't
+    let mut dst = cmake::Config::new(&source_dir);
+
+    // capnp runs wherever the macro is expanded, so it's always built for the host. Flags, toolchains and
+    // sysroots meant for the target would produce a binary that doesn't run, or doesn't link at all
+    let host = env::var("HOST").context("Cargo did not set $HOST. this should be impossible.")?;
+    dst.target(&host).host(&host);
+    for var in [
+        "RUSTFLAGS",
+        "CARGO_ENCODED_RUSTFLAGS",
+        "TARGET_CC",
+        "TARGET_CXX",
+        "TARGET_CFLAGS",
+        "TARGET_CXXFLAGS",
+        "TARGET_AR",
+        "CMAKE_TOOLCHAIN_FILE",
+        "CMAKE_SYSROOT",
+        "PKG_CONFIG_SYSROOT_DIR",
+    ] {
+        if env::var_os(var).is_some() {
+            println!("not forwarding ${var} to the capnp build, it describes the target");
+            env::remove_var(var);
+        }
+    }
+    // the compiler needs neither, and detecting them could pick up libraries of the target
+    dst.define("WITH_OPENSSL", "OFF").define("WITH_ZLIB", "OFF");
+
+    if which::which("ninja").is_ok() {
+        dst.generator("Ninja");
+    }
+
+    // it would be nice to be able to use mold
+
+    #[cfg(target_os = "windows")]
+    dst.cxxflag("/EHsc");
+
+    // capnp is only a build tool, so it's optimized even in debug builds unless someone is debugging capnp
+    // itself. profile() sets CMAKE_BUILD_TYPE, and the configuration for multi-config generators
+    let build_type = env::var("CAPNP_IMPORT_BUILD_TYPE").unwrap_or_else(|_| "Release".to_owned());
+    dst.profile(&build_type);
+
+    // cmake builds with $NUM_JOBS jobs, or as many as cargo's jobserver allows with make. Compiling capnp
+    // takes a lot of memory per job, so small builders can cap that below what cargo runs in parallel
+    if let Ok(jobs) = env::var("CAPNP_IMPORT_BUILD_JOBS") {
+        let jobs: usize = jobs
+            .parse()
+            .ok()
+            .filter(|&jobs| jobs > 0)
+            .with_context(|| {
+                format!("$CAPNP_IMPORT_BUILD_JOBS must be a positive number, not {jobs:?}")
+            })?;
+        let num_jobs = env::var("NUM_JOBS").ok().and_then(|jobs| jobs.parse().ok());
+        let jobs = num_jobs.map_or(jobs, |num_jobs: usize| num_jobs.min(jobs));
+        println!("building capnp with {jobs} jobs");
+        env::set_var("NUM_JOBS", jobs.to_string());
+        // with the jobserver, cmake would ignore $NUM_JOBS
+        env::remove_var("CARGO_MAKEFLAGS");
+    }
+
+    // a binary that doesn't depend on the C++ runtime of the machine it was built on can be cached and
+    // shared. macOS always ships libc++, so there's nothing to do there
+    if cfg!(feature = "static-link") {
+        dst.define("BUILD_SHARED_LIBS", "OFF");
+        if cfg!(target_os = "windows") {
+            dst.static_crt(true);
+        } else if cfg!(target_os = "linux") {
+            dst.define("CMAKE_EXE_LINKER_FLAGS", "-static-libgcc -static-libstdc++");
+        }
+    }
+
+    // place the capnproto binary in $OUT_DIR, next to where binary_decision.rs
+    // is intended to go
+    let bin = if cfg!(target_os = "windows") {
+        RelativePathBuf::from("bin/capnp.exe")
+    } else if cfg!(target_os = "linux") || cfg!(target_os = "macos") {
+        RelativePathBuf::from("bin/capnp")
+    } else {
+        panic!("Sorry, capnp-import does not support your operating system.");
+    };
+    // cmake only rebuilds what changed, so a binary left by an earlier run is reused as is
+    let cached = bin.to_path(out_dir).is_file();
+
+    let dst = dst.define("BUILD_TESTING", "OFF").build();
+
+    assert_eq!(*out_dir, dst);
+
+    if cached {
+        Ok(CapnprotoAcquired::Cached(bin))
+    } else {
+        Ok(CapnprotoAcquired::Locally(bin))
+    }
+}
@@ -0,0 +1,4400 @@
+//! Compiles Cap'n Proto schemas with an embedded copy of the official compiler (capnp), either on behalf of
+//! the `capnp_import!` macro or directly from a build script.
+
+use anyhow::{anyhow, bail};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{env, fs, path::Path};
+use wax::{BuildError, Glob, Pattern};
+
+use anyhow::Context;
+
+mod rpc_wrappers;
+#[cfg(feature = "serde")]
+mod serde_shims;
+
+include!(concat!(env!("OUT_DIR"), "/extract_bin.rs"));
+
+/// The version of the embedded capnp, such as `"0.11.0"`. Only a system installation of the exact version
+/// this crate targets is ever embedded, so this is also the targeted version.
+pub fn capnp_version() -> &'static str {
+    CAPNP_VERSION
+}
+
+/// How the embedded capnp was obtained when this crate was built, and where from, such as
+/// `"system: /usr/bin/capnp"` or `"built from the vendored sources: ..."`.
+pub fn capnp_source() -> &'static str {
+    CAPNP_SOURCE
+}
+
+/// The SHA-256 of the embedded capnp binary in lowercase hex, computed when this crate was built. Unlike
+/// [`capnp_version`] this tells two builds of the same version apart, so comparing it across machines shows
+/// whether they compiled schemas with the identical binary.
+pub fn capnp_binary_hash() -> &'static str {
+    CAPNP_BINARY_HASH
+}
+
+/// A report of how the embedded capnp was obtained when this crate was built, for troubleshooting and bug
+/// reports.
+///
+/// ```no_run
+/// // Inside build.rs
+/// println!("cargo:warning={}", capnp_import_core::diagnose().replace('\n', "; "));
+/// ```
+pub fn diagnose() -> String {
+    let mut report = format!(
+        "capnp-import-core {} requires capnp {}\n",
+        env!("CARGO_PKG_VERSION"),
+        REQUIRED_CAPNP_VERSION
+    );
+    report += &match SYSTEM_CAPNP {
+        Some((path, Some(version))) => {
+            let reported = version
+                .split_once("Cap'n Proto version ")
+                .map_or(version, |(_, version)| version.trim());
+            let verdict = if reported == REQUIRED_CAPNP_VERSION {
+                "meets the requirement"
+            } else {
+                "does not meet the requirement"
+            };
+            format!("system capnp: {} ({}, {})\n", path, version, verdict)
+        }
+        Some((path, None)) => format!("system capnp: {} (could not obtain its version)\n", path),
+        None => "system capnp: not found\n".to_owned(),
+    };
+    report += &format!(
+        "built from source: {}\n",
+        if BUILT_FROM_SOURCE { "yes" } else { "no" }
+    );
+    report += &format!("embedded capnp: {} ({})\n", CAPNP_BINARY, CAPNP_VERSION);
+    report += &format!("embedded capnp sha256: {}\n", CAPNP_BINARY_HASH);
+    report += &format!("acquired: {}\n", CAPNP_SOURCE);
+    report += &format!(
+        "standard include dir: {}\n",
+        CAPNP_INCLUDE_DIR.unwrap_or("not found")
+    );
+    report
+}
+
+/// Build script entry point, compiling every schema matched by a set of glob patterns into `OUT_DIR`
+/// and writing a helper file that wraps each of them in a module.
+///
+/// ```no_run
+/// // Inside build.rs
+/// let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+/// capnp_import_core::Config::new()
+///     .pattern("**/*.capnp")
+///     .root(out_dir.join("generated-schemas"))
+///     .build()
+///     .unwrap();
+/// ```
+/// ```ignore
+/// // Inside main.rs
+/// include!(concat!(env!("OUT_DIR"), "/capnp_include.rs"));
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    patterns: Vec<String>,
+    indexes: Vec<PathBuf>,
+    annotations_dirs: Vec<PathBuf>,
+    schema_crates: Vec<String>,
+    roots: Vec<PathBuf>,
+    groups: Vec<Group>,
+    inline_mod: bool,
+    order: Order,
+    incremental: bool,
+    duplicate_ids: Severity,
+    case_collisions: Severity,
+    lift_docs: bool,
+    header: Header,
+    capnp_crate: Option<String>,
+    append: bool,
+    formatter: Option<String>,
+    formatter_failures: Severity,
+    verbose: bool,
+    single_file: bool,
+    path_mod: bool,
+    nested: bool,
+    empty_files: Severity,
+    strict: bool,
+    strip_prefix: Option<PathBuf>,
+    src_prefix: Option<PathBuf>,
+    deny_warnings: bool,
+    codec_helpers: bool,
+    exposed: Vec<String>,
+    reexports: Vec<(String, String)>,
+    emitter: CustomEmitter,
+    feature: Option<String>,
+    cfg: Option<String>,
+    import_resolver: CustomResolver,
+    serde_shims: bool,
+    rpc_wrappers: bool,
+    runtime: Option<String>,
+    excludes: Vec<String>,
+    extensions: Vec<String>,
+    sniff_schemas: bool,
+    allows: Vec<String>,
+    visibility: Option<String>,
+    codegen_only: bool,
+    plugin: Option<Plugin>,
+    parent: Option<String>,
+    generated_src: bool,
+    best_effort: bool,
+    check_imports: bool,
+    manifest: Option<PathBuf>,
+    depfile: Option<PathBuf>,
+    substitutions: BTreeMap<String, String>,
+    substitute_env: bool,
+    compiler: Option<PathBuf>,
+    rustfmt_generated: bool,
+    dry_run: bool,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a glob pattern, matched against the paths below each root.
+    pub fn pattern(&mut self, pattern: impl AsRef<str>) -> &mut Self {
+        self.patterns.push(pattern.as_ref().to_owned());
+        self
+    }
+
+    /// Adds an index file listing schemas to compile, one path per line relative to each root, in place
+    /// of matching patterns. Blank lines and lines starting with `#` are skipped, and every listed schema
+    /// has to exist. With [`Order::Input`] the schemas are emitted in the order they are listed.
+    pub fn index(&mut self, index: impl AsRef<Path>) -> &mut Self {
+        self.indexes.push(index.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a directory to walk for schemas. Relative roots are resolved against the crate root, and absolute
+    /// ones, such as a directory of schemas generated earlier in the same build script, are walked as-is.
+    /// When no root is given, the crate root is walked. Otherwise every root is also an import path of the
+    /// schemas found in it, and a schema found below several roots is only compiled from the first of them.
+    pub fn root(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.roots.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a group of schemas that is compiled separately, with its own root and import paths. The
+    /// modules of all groups end up in the same helper.
+    pub fn group(&mut self, group: Group) -> &mut Self {
+        self.groups.push(group);
+        self
+    }
+
+    /// Adds a directory of schemas defining annotations, which absolute imports of every group are
+    /// resolved against. The annotations of capnpc itself can always be imported as `/rust.capnp`. This can
+    /// also be a directory of the matched schemas themselves, such as `proto` for schemas importing
+    /// `"/myorg/base.capnp"`, and the schemas inside it are then generated after their path below it.
+    pub fn annotations_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.annotations_dirs.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Resolves absolute imports against the schemas of a crate built with [`Config::build_schema_crate`],
+    /// `links` being the `links` key of its package. Cargo hands its import paths to the build scripts of
+    /// the crates depending on it directly as `DEP_<LINKS>_CAPNP_IMPORT_PATHS`, so this only works in one,
+    /// and fails when the variable isn't set. The generated code refers to the modules of those schemas
+    /// from the crate root, so re-export them there, such as with `pub use schemas::base_capnp;`.
+    pub fn import_schema_crate(&mut self, links: impl AsRef<str>) -> &mut Self {
+        self.schema_crates.push(links.as_ref().to_owned());
+        self
+    }
+
+    /// Splices the generated code directly into each module instead of `include!`ing it from `OUT_DIR`.
+    /// This lets IDEs resolve the generated types without a prior build, at the cost of a much larger
+    /// helper. Off by default.
+    pub fn inline_mod(&mut self, inline_mod: bool) -> &mut Self {
+        self.inline_mod = inline_mod;
+        self
+    }
+
+    /// Sets the order the modules are emitted in. Defaults to [`Order::Lexicographic`].
+    pub fn order(&mut self, order: Order) -> &mut Self {
+        self.order = order;
+        self
+    }
+
+    /// Only recompiles the schemas whose contents changed since they were last compiled into `OUT_DIR`,
+    /// which speeds up the edit-compile loop on large schema sets. The content hash of every compiled
+    /// schema is kept in `OUT_DIR/capnp_import_state`, so touching a file doesn't recompile it. So is the
+    /// version of capnp along with a hash of the options that change the generated code, such as
+    /// [`Config::codec_helpers`], and everything is recompiled when either changes. Changes to
+    /// the schemas a file imports are not tracked, and without `OUT_DIR` or with [`Config::inline_mod`]
+    /// everything is compiled as usual. Off by default.
+    pub fn incremental(&mut self, incremental: bool) -> &mut Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Sets what happens when two matched schemas share a file id, which usually means a schema was
+    /// copied without generating a new id. Defaults to [`Severity::Warn`].
+    pub fn duplicate_ids(&mut self, severity: Severity) -> &mut Self {
+        self.duplicate_ids = severity;
+        self
+    }
+
+    /// Sets what happens when two matched schemas have paths that only differ in case, like `Foo.capnp`
+    /// and `foo.capnp`. They are distinct files on case-sensitive filesystems, but collide on the usual
+    /// ones of macOS and Windows, so such schemas build differently across machines. Module names keep the
+    /// case capnpc gives them either way. Defaults to [`Severity::Warn`].
+    pub fn case_collisions(&mut self, severity: Severity) -> &mut Self {
+        self.case_collisions = severity;
+        self
+    }
+
+    /// Sets what happens to matched schemas that are empty or only contain whitespace, which capnp would
+    /// only reject with a vague parse error. Unless denied, they are skipped. Defaults to [`Severity::Warn`].
+    pub fn empty_files(&mut self, severity: Severity) -> &mut Self {
+        self.empty_files = severity;
+        self
+    }
+
+    /// Turns the comment block at the top of each schema into the doc comment of its module, so IDEs show
+    /// it when hovering the module. Off by default.
+    pub fn lift_docs(&mut self, lift_docs: bool) -> &mut Self {
+        self.lift_docs = lift_docs;
+        self
+    }
+
+    /// Replaces the comment at the top of the helper, such as with a note not to edit a committed copy of
+    /// it. Each line of `header` becomes a `//` comment. Defaults to a note that the file is autogenerated.
+    pub fn header(&mut self, header: impl AsRef<str>) -> &mut Self {
+        self.header = Header(Some(header.as_ref().to_owned()));
+        self
+    }
+
+    /// Leaves out the comment at the top of the helper.
+    pub fn no_header(&mut self) -> &mut Self {
+        self.header = Header(None);
+        self
+    }
+
+    /// Sets the path the generated code reaches the `capnp` crate through, such as `::capnp_renamed` for a
+    /// renamed dependency or `crate::deps::capnp` for a re-export. Defaults to `::capnp`.
+    pub fn capnp_crate(&mut self, path: impl AsRef<str>) -> &mut Self {
+        self.capnp_crate = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Runs `command` on every generated file, with the path of the file as its last argument, such as
+    /// `rustfmt --edition 2021`. The arguments are separated by whitespace and can't be quoted.
+    pub fn formatter(&mut self, command: impl AsRef<str>) -> &mut Self {
+        self.formatter = Some(command.as_ref().to_owned());
+        self
+    }
+
+    /// Sets what happens when the [`Config::formatter`] can't be run or exits with an error. Defaults to
+    /// [`Severity::Warn`].
+    pub fn formatter_failures(&mut self, severity: Severity) -> &mut Self {
+        self.formatter_failures = severity;
+        self
+    }
+
+    /// Concatenates the generated code of all schemas, each in its module, into a single file in `OUT_DIR`
+    /// that the helper `include!`s, instead of `include!`ing one file per schema. Ordering and
+    /// deduplication apply as usual. Without `OUT_DIR`, or with [`Config::inline_mod`] or [`Config::append`],
+    /// the code is spliced into the helper instead. Off by default.
+    pub fn single_file(&mut self, single_file: bool) -> &mut Self {
+        self.single_file = single_file;
+        self
+    }
+
+    /// Declares each module as `#[path = "..."] pub mod foo_capnp;` pointing at the generated file, instead
+    /// of `include!`ing it into an inline module. Some IDEs resolve the generated types better this way,
+    /// and errors point into the generated file. As attributes can't use `env!`, the path is absolute.
+    /// Only applies when the code is kept in `OUT_DIR`, and not with [`Config::single_file`]. Off by default.
+    pub fn path_mod(&mut self, path_mod: bool) -> &mut Self {
+        self.path_mod = path_mod;
+        self
+    }
+
+    /// Nests the module of each schema in a module for every directory between it and its root, so
+    /// `net/rpc.capnp` becomes `net::rpc_capnp`, instead of putting every module side by side. The code
+    /// capnpc generates refers to these modules from the crate root, so the helper has to be included at
+    /// the top level, and imports across directories only resolve for schemas annotated with
+    /// `$Rust.parentModule`. Off by default.
+    pub fn nested(&mut self, nested: bool) -> &mut Self {
+        self.nested = nested;
+        self
+    }
+
+    /// Checks that every matched schema ended up in exactly one module, and that no two schemas share a
+    /// module, failing with the schemas involved otherwise, as for `foo.capnp` and `foo.capnproto`, which
+    /// both generate `foo_capnp`. Off by default.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Leaves out the modules for the directories of `prefix`, relative to each root, when nesting modules
+    /// with [`Config::nested`], so `com/example/net/rpc.capnp` becomes `net::rpc_capnp` with a prefix of
+    /// `com/example`. The schemas are still compiled from where they are, and schemas outside of the prefix
+    /// keep their full module path. As this can make schemas share a module, the modules are always
+    /// checked like with [`Config::strict`]. Flat modules are named after the file stem only anyway.
+    pub fn strip_prefix(&mut self, prefix: impl AsRef<Path>) -> &mut Self {
+        self.strip_prefix = Some(prefix.as_ref().to_owned());
+        self
+    }
+
+    /// Writes the code generated for flat modules to its path below `prefix`, relative to each root, instead of
+    /// below the deepest directory all the schemas of the root are in, which is the default. That only
+    /// moves the generated files, so schemas outside of the prefix are generated relative to the root. With
+    /// [`Config::nested`] the generated files follow the modules, see [`Config::strip_prefix`] instead.
+    pub fn src_prefix(&mut self, prefix: impl AsRef<Path>) -> &mut Self {
+        self.src_prefix = Some(prefix.as_ref().to_owned());
+        self
+    }
+
+    /// Fails the build with the warnings capnp reported, such as about overlapping import paths, instead of
+    /// just passing them on. Off by default.
+    pub fn deny_warnings(&mut self, deny_warnings: bool) -> &mut Self {
+        self.deny_warnings = deny_warnings;
+        self
+    }
+
+    /// Adds `read_message`, `write_message`, `read_message_packed` and `write_message_packed` functions to
+    /// the module of every schema that declares a single struct at the top level, reading and writing
+    /// messages with that struct as their root. Schemas with several structs, or a generic one, are left
+    /// alone rather than guessing their root. Off by default.
+    pub fn codec_helpers(&mut self, codec_helpers: bool) -> &mut Self {
+        self.codec_helpers = codec_helpers;
+        self
+    }
+
+    /// Re-exports the top level type called `name`, such as `Person`, next to the modules, and makes the
+    /// modules themselves `pub(crate)`, so only the exposed types are part of the public API. The code of
+    /// every type is still generated and compiled, this only limits what is reachable from outside the
+    /// crate. Structs and interfaces are re-exported as their modules, such as `person`, and naming a type
+    /// that no schema declares at the top level is an error.
+    pub fn expose(&mut self, name: impl AsRef<str>) -> &mut Self {
+        self.exposed.push(name.as_ref().to_owned());
+        self
+    }
+
+    /// Adds `pub(crate) use path as name;` next to the modules, to give a generated type, such as
+    /// `person_capnp::person`, a name of your choosing at the crate root. `path` starts with one of the
+    /// modules, inside [`Config::parent`] if there is one, and the names have to be identifiers distinct
+    /// from each other and from the modules. Unlike [`Config::expose`] this leaves the modules as they are.
+    pub fn reexport(&mut self, path: impl AsRef<str>, name: impl AsRef<str>) -> &mut Self {
+        self.reexports
+            .push((path.as_ref().to_owned(), name.as_ref().to_owned()));
+        self
+    }
+
+    /// Adds a glob pattern of schemas to leave out, matched against the paths below each root, including
+    /// those of groups. Schemas listed in an index are left out too if they match.
+    pub fn exclude(&mut self, pattern: impl AsRef<str>) -> &mut Self {
+        self.excludes.push(pattern.as_ref().to_owned());
+        self
+    }
+
+    /// Generates the code with `plugin` instead of the bundled capnpc, for a custom or forked Rust code
+    /// generator. It has to write a file named like capnpc does for each schema, `foo_capnp.rs` for
+    /// `foo.capnp`, for the modules to find it.
+    pub fn plugin(&mut self, plugin: Plugin) -> &mut Self {
+        self.plugin = Some(plugin);
+        self
+    }
+
+    /// Declares every module inside a single `pub mod name`, such as `proto`, instead of at the top level of
+    /// the crate, to keep it tidy. The generated code refers to the modules through it, so the helper still
+    /// has to be included at the crate root. Two schemas ending up as modules of the same name in it are
+    /// an error.
+    pub fn parent(&mut self, name: impl AsRef<str>) -> &mut Self {
+        self.parent = Some(name.as_ref().to_owned());
+        self
+    }
+
+    /// Adds an extension, such as `capnp`, that schemas have to have, as a safety net against patterns like
+    /// `**/*` that also match other files. Files matched by the patterns or listed in an index are left out
+    /// unless they have one of the extensions, and without any every matched file is compiled.
+    pub fn extension(&mut self, extension: impl AsRef<str>) -> &mut Self {
+        let extension = extension.as_ref();
+        self.extensions
+            .push(extension.strip_prefix('.').unwrap_or(extension).to_owned());
+        self
+    }
+
+    /// Also recognizes schemas by their content: a matched file without one of the extensions, see
+    /// [`Config::extension`], is still compiled when its first line declares a valid file id, such as
+    /// `@0xdbb9ad1f14bf0b36;`, and other matched files are left out, so `**/*` picks out the schemas among
+    /// files of any name. Reads the start of every such file, so it's off by default.
+    pub fn sniff_schemas(&mut self, sniff_schemas: bool) -> &mut Self {
+        self.sniff_schemas = sniff_schemas;
+        self
+    }
+
+    /// Adds `pub const GENERATED_SRC: &str` next to the modules, holding the code generated for all of them
+    /// as text, for tools that want to inspect it at runtime rather than only compile it. Off by default.
+    pub fn generated_src(&mut self, generated_src: bool) -> &mut Self {
+        self.generated_src = generated_src;
+        self
+    }
+
+    /// Skips schemas that fail to compile, with a warning, instead of failing the build, so work can go on
+    /// while one of them is mid-edit. When the schemas compiled together fail, each of them is compiled on
+    /// its own to tell which are broken, and the modules of those are left out. Code using their types then
+    /// fails to compile with little hint as to why, and schemas importing them are skipped too, so this is
+    /// only meant for development. Off by default.
+    pub fn best_effort(&mut self, best_effort: bool) -> &mut Self {
+        self.best_effort = best_effort;
+        self
+    }
+
+    /// Writes a Make-style dependency file to `path`, resolved against the output directory when relative, with
+    /// a rule for every generated file depending on its schema and everything that transitively imports,
+    /// for build systems to rerun the build when any of them changes. Like `-MP` of C compilers, every
+    /// prerequisite also gets an empty rule, so deleting one of them doesn't break the build. Paths are
+    /// written with forward slashes, also on Windows, and with spaces, `#`, `:` and `$` escaped.
+    pub fn depfile(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.depfile = Some(path.as_ref().into());
+        self
+    }
+
+    /// Replaces every `{{key}}` in the matched schemas with `value` before compiling them, such as
+    /// `{{VERSION}}` with a version number. The schemas are copied into a temporary directory, along with
+    /// the files they import relatively, and compiled from there, so the originals are left untouched and
+    /// the modules are still named after them. Keys are made of letters, digits and underscores, and a
+    /// placeholder with a key that has no value fails the build naming the key and the schema.
+    pub fn substitute(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> &mut Self {
+        self.substitutions
+            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+        self
+    }
+
+    /// Replaces placeholders without a value from [`Config::substitute`] with the environment variable of
+    /// the same name, if it is set. Build scripts are rerun when those variables change. Off by default.
+    pub fn substitute_env(&mut self, substitute_env: bool) -> &mut Self {
+        self.substitute_env = substitute_env;
+        self
+    }
+
+    /// Resolves the imports of every schema against its directory and the import paths before compiling, and
+    /// fails naming each import that doesn't resolve and the schema importing it, which capnp reports less
+    /// clearly. Off by default.
+    pub fn check_imports(&mut self, check_imports: bool) -> &mut Self {
+        self.check_imports = check_imports;
+        self
+    }
+
+    /// Writes a JSON manifest of how the code was generated to `path`, resolved against the output directory
+    /// when relative: the compiler binary and its version, the import paths it was given, every schema with
+    /// the file generated for it, and the files they transitively import, under the names capnp gave them.
+    /// Schemas an incremental build didn't recompile contribute no imports.
+    pub fn manifest(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.manifest = Some(path.as_ref().into());
+        self
+    }
+
+    /// Adds `#[allow(lint)]` to every module, such as `clippy::all` to keep the lints of a crate from
+    /// applying to the generated code.
+    pub fn allow(&mut self, lint: impl AsRef<str>) -> &mut Self {
+        self.allows.push(lint.as_ref().to_owned());
+        self
+    }
+
+    /// Adds `#[rustfmt::skip]` to every module, so `cargo fmt` doesn't spend its time on the generated
+    /// code, which can be huge. Turn this off when running a [`Config::formatter`] over the generated files
+    /// and the modules should be formatted like the rest of the crate. On by default.
+    pub fn rustfmt_skip(&mut self, rustfmt_skip: bool) -> &mut Self {
+        self.rustfmt_generated = !rustfmt_skip;
+        self
+    }
+
+    /// Declares every module with `visibility`, such as `pub(crate)`, instead of `pub`. Overrides the
+    /// `pub(crate)` of [`Config::expose`], whose re-exports stay `pub`.
+    pub fn visibility(&mut self, visibility: impl AsRef<str>) -> &mut Self {
+        self.visibility = Some(visibility.as_ref().to_owned());
+        self
+    }
+
+    /// Reads defaults for this configuration from the TOML file at `path`, which does nothing if there is no
+    /// such file. Lists in the file are added to those set so far and everything else replaces what was set
+    /// so far, so options set afterwards take precedence. The file can set
+    ///
+    /// - `import_paths`, a list of directories passed to [`Config::annotations_dir`],
+    /// - `modules`, one of `"include"`, `"inline"`, `"path"` or `"single_file"`, choosing between
+    ///   `include!`ing the generated code, [`Config::inline_mod`], [`Config::path_mod`] and
+    ///   [`Config::single_file`],
+    /// - `exclude`, a list of patterns passed to [`Config::exclude`],
+    /// - `allow`, a list of lints passed to [`Config::allow`],
+    /// - `visibility`, passed to [`Config::visibility`].
+    ///
+    /// ```toml
+    /// import_paths = ["schemas/annotations"]
+    /// modules = "path"
+    /// exclude = ["**/legacy/*.capnp"]
+    /// allow = ["clippy::all"]
+    /// visibility = "pub(crate)"
+    /// ```
+    pub fn defaults_file(&mut self, path: impl AsRef<Path>) -> anyhow::Result<&mut Self> {
+        let path = path.as_ref();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(self),
+            Err(e) => return Err(e).with_context(|| format!("could not read {:?}", path)),
+        };
+        let table: toml::Table =
+            toml::from_str(&contents).with_context(|| format!("could not parse {:?}", path))?;
+        for (key, value) in &table {
+            self.default_option(key, value)
+                .with_context(|| format!("invalid `{}` in {:?}", key, path))?;
+        }
+        Ok(self)
+    }
+
+    /// Applies the option `key` of a defaults file, see [`Config::defaults_file`].
+    fn default_option(&mut self, key: &str, value: &toml::Value) -> anyhow::Result<()> {
+        let strings = || -> anyhow::Result<Vec<&str>> {
+            value
+                .as_array()
+                .and_then(|values| values.iter().map(toml::Value::as_str).collect())
+                .ok_or_else(|| anyhow!("expected a list of strings"))
+        };
+        let string = || value.as_str().ok_or_else(|| anyhow!("expected a string"));
+        match key {
+            "import_paths" => {
+                for dir in strings()? {
+                    self.annotations_dir(dir);
+                }
+            }
+            "exclude" => {
+                for pattern in strings()? {
+                    self.exclude(pattern);
+                }
+            }
+            "allow" => {
+                for lint in strings()? {
+                    self.allow(lint);
+                }
+            }
+            "visibility" => {
+                self.visibility(string()?);
+            }
+            "modules" => {
+                let modules = string()?;
+                self.inline_mod(modules == "inline")
+                    .path_mod(modules == "path")
+                    .single_file(modules == "single_file");
+                if !["include", "inline", "path", "single_file"].contains(&modules) {
+                    bail!("expected one of \"include\", \"inline\", \"path\" or \"single_file\", not {:?}", modules);
+                }
+            }
+            _ => bail!("unknown option"),
+        }
+        Ok(())
+    }
+
+    /// Gates every module behind `#[cfg(feature = "name")]`, so the schemas only become part of the crate
+    /// when that cargo feature is enabled. [`Config::build`] also skips compiling them when it's disabled,
+    /// as cargo tells build scripts about enabled features through `CARGO_FEATURE_<NAME>`, the name in
+    /// upper case with dashes turned into underscores. The macro isn't told, so it still compiles them.
+    pub fn feature(&mut self, name: impl AsRef<str>) -> &mut Self {
+        self.feature = Some(name.as_ref().to_owned());
+        self
+    }
+
+    /// Gates the modules behind `#[cfg(predicate)]`, for any predicate such as `feature = "dev-schemas"` or
+    /// `all(unix, not(debug_assertions))`. With [`Config::parent`] only the parent module is gated, so schema
+    /// sets for different build profiles each become a module of their own. Unlike [`Config::feature`] this
+    /// never skips compiling the schemas.
+    pub fn cfg(&mut self, predicate: impl AsRef<str>) -> &mut Self {
+        self.cfg = Some(predicate.as_ref().trim().to_owned());
+        self
+    }
+
+    /// Calls `resolver` before the schemas of each group are compiled, to make imports available that aren't
+    /// on disk up front, such as schemas fetched on demand. It can write them into the scratch directory it's
+    /// given, which is deleted after compilation, and returns the directories to add as import paths.
+    ///
+    /// ```no_run
+    /// # use std::path::{Path, PathBuf};
+    /// capnp_import_core::Config::new()
+    ///     .pattern("schema/*.capnp")
+    ///     .import_resolver(|_schemas: &[PathBuf], scratch: &Path| {
+    ///         std::fs::write(scratch.join("common.capnp"), "@0xc9d2b6a3a58e4f05;\n")?;
+    ///         Ok(vec![scratch.to_owned()])
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn import_resolver(
+        &mut self,
+        resolver: impl ImportResolver + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.import_resolver = CustomResolver(Some(Arc::new(resolver)));
+        self
+    }
+
+    /// Declares the module of every generated file kept in `OUT_DIR` with `emitter`, in place of
+    /// [`append_path`] or [`append_path_mod`], to control its name, visibility, attributes or wrapping.
+    /// The emitter decides the visibility, so [`Config::visibility`] doesn't apply to its modules. Inlined
+    /// and merged code is always wrapped in a plain module.
+    ///
+    /// ```no_run
+    /// # use std::path::Path;
+    /// capnp_import_core::Config::new()
+    ///     .pattern("schema/*.capnp")
+    ///     .emitter(|file_path: &Path, output_path: &Path| {
+    ///         let module = capnp_import_core::append_path(file_path, output_path, "pub")?;
+    ///         Ok(format!("#[allow(clippy::all)]\n{}", module))
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn emitter(&mut self, emitter: impl Emitter + Send + Sync + 'static) -> &mut Self {
+        self.emitter = CustomEmitter(Some(Arc::new(emitter)));
+        self
+    }
+
+    /// Adds a `serde_shims` module to the module of every schema, with a mirror of each of its structs and
+    /// enums that derives `serde::Serialize` and `serde::Deserialize`. Nested structs are mirrored as
+    /// `PersonPhoneNumber`, fields hold `String`s, `Vec`s and other mirrors, readers convert into mirrors
+    /// with `TryFrom` and mirrors are written back with their `build` method. Structs with unions, groups,
+    /// generic parameters, interfaces, `AnyPointer`s or types of other schemas aren't mirrored. The crate
+    /// using the generated code needs `serde` with its `derive` feature, and this needs the `serde` feature
+    /// of this crate. Off by default.
+    pub fn serde_shims(&mut self, serde_shims: bool) -> &mut Self {
+        self.serde_shims = serde_shims;
+        self
+    }
+
+    /// Adds a `{method}_async` method to the client of every interface for each of its methods, which builds
+    /// the params with a closure, sends the request and returns the promise of the response, a future to
+    /// `.await` with whatever runtime drives capnp-rpc. A method of `interface Vault` called `get` gets
+    /// `vault::Client::get_async(|mut params| params.set_key("id"))`, and one without params takes no
+    /// closure. Only well-defined shapes are covered: methods of generic interfaces, or of interfaces in
+    /// generic structs, generic methods, and methods taking or returning a named struct rather than a
+    /// parameter list get no wrapper, so use their `{method}_request` as before. Off by default.
+    pub fn rpc_wrappers(&mut self, rpc_wrappers: bool) -> &mut Self {
+        self.rpc_wrappers = rpc_wrappers;
+        self
+    }
+
+    /// Declares the version of the `capnp` runtime crate the generated code has to compile against, such as
+    /// `"0.14"` or `"0.14.11"`. Code generators only ever target a single runtime, so this fails early with an
+    /// error naming the supported one instead of generating code that references items the runtime lacks.
+    /// Defaults to [`CAPNP_RUNTIME`], the runtime this crate generates code for.
+    pub fn runtime(&mut self, version: impl AsRef<str>) -> &mut Self {
+        self.runtime = Some(version.as_ref().to_owned());
+        self
+    }
+
+    /// Runs capnp with `--verbose`, printing everything it reports as warnings. When compilation fails, the
+    /// error also shows the command line capnp was run with. Off by default.
+    pub fn verbose(&mut self, verbose: bool) -> &mut Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Runs `compiler` in place of capnp, as `capnp compile -o -` with `CAPNP_IMPORT_OUTPUT_DIR` set to the
+    /// directory capnpc writes to, and without checking its version. Only meant for the tests of this crate,
+    /// which stand in a script for capnp.
+    #[doc(hidden)]
+    pub fn compiler(&mut self, compiler: impl AsRef<Path>) -> &mut Self {
+        self.compiler = Some(compiler.as_ref().to_owned());
+        self
+    }
+
+    /// Merges the modules into the helper left by earlier calls to [`Config::build`] instead of replacing
+    /// it, so several configurations, say for schemas from different sources, can share one helper. A
+    /// module replaces an earlier one of the same name, and modules stay sorted by name. The helper is
+    /// locked while it's merged, so builds running in parallel don't lose each other's modules. The lock is
+    /// the file `capnp_include.rs.lock` next to it, which is left there, since removing it could let a build
+    /// waiting on it merge at the same time as a later one. Only helpers written in this mode can be merged
+    /// into. Off by default.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Whether the helper declaring the modules is written, on by default. Turning it off is a codegen-only
+    /// mode for pipelines where another tool picks up the generated `.rs` files: [`Config::build_files`] and
+    /// [`Config::generate_files_into`] only compile the schemas and return the paths of the generated files,
+    /// and the helper whose path [`Config::build`] and [`Config::generate_into`] return isn't written.
+    pub fn emit_modules(&mut self, emit_modules: bool) -> &mut Self {
+        self.codegen_only = !emit_modules;
+        self
+    }
+
+    /// Compiles every matched schema into `OUT_DIR` and writes `OUT_DIR/capnp_include.rs`, returning its path.
+    /// Cargo is told to rerun the build script whenever a root or a matched schema changes, except for
+    /// schemas inside `OUT_DIR`, which can only change when the build script itself runs.
+    pub fn build(&self) -> anyhow::Result<PathBuf> {
+        self.build_files()?;
+        Ok(build_out_dir()?.join("capnp_include.rs"))
+    }
+
+    /// Like [`Config::build`], but returns the paths of the files generated into `OUT_DIR`, see
+    /// [`Config::emit_modules`].
+    pub fn build_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let out_dir = build_out_dir()?;
+
+        if let Some(feature) = &self.feature {
+            let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+            if env::var_os(var).is_none() {
+                let helper_path = out_dir.join("capnp_include.rs");
+                if !self.codegen_only && (!self.append || !helper_path.exists()) {
+                    fs::write(&helper_path, header_comment(self))?;
+                }
+                return Ok(Vec::new());
+            }
+        }
+
+        for group in self.groups() {
+            if !group.root.starts_with(&out_dir) {
+                println!("cargo:rerun-if-changed={}", group.root.display());
+            }
+            for index in &group.indexes {
+                println!("cargo:rerun-if-changed={}", index.display());
+            }
+            for file in group.files(self.order)? {
+                if !file.starts_with(&out_dir) {
+                    println!("cargo:rerun-if-changed={}", file.display());
+                }
+            }
+        }
+
+        for links in &self.schema_crates {
+            println!(
+                "cargo:rerun-if-env-changed={}",
+                schema_crate_var(links, "PATHS")
+            );
+        }
+
+        self.generate_files_into(&out_dir)
+    }
+
+    /// Like [`Config::build`], for a crate owning schemas that other crates of a workspace import. Its
+    /// package needs a `links` key, under which the directory of the helper is passed on to the build
+    /// scripts of the crates depending on it as `DEP_<LINKS>_CAPNP_IMPORT_DIR`, and the roots and import
+    /// paths of its schemas as `DEP_<LINKS>_CAPNP_IMPORT_PATHS`, see [`Config::import_schema_crate`]. The
+    /// helper declares the generated files by their absolute path, so a dependent can also `include!` it
+    /// on its own instead of using the modules of this crate.
+    ///
+    /// ```no_run
+    /// // Inside the build.rs of a crate with `links = "schemas"`
+    /// capnp_import_core::Config::new()
+    ///     .root("schema")
+    ///     .pattern("**/*.capnp")
+    ///     .build_schema_crate()
+    ///     .unwrap();
+    /// ```
+    pub fn build_schema_crate(&self) -> anyhow::Result<PathBuf> {
+        let mut config = self.clone();
+        if config.emitter.0.is_none() {
+            config.path_mod = true;
+        }
+        config.inline_mod = false;
+        config.single_file = false;
+        config.codegen_only = false;
+        let helper_path = config.build()?;
+        for line in self.schema_crate_metadata(&build_out_dir()?)? {
+            println!("{}", line);
+        }
+        Ok(helper_path)
+    }
+
+    /// The lines telling cargo what [`Config::build_schema_crate`] passes on to dependents, `out_dir` being
+    /// the directory of the helper.
+    fn schema_crate_metadata(&self, out_dir: &Path) -> anyhow::Result<Vec<String>> {
+        let mut import_paths = Vec::new();
+        for group in self.groups() {
+            for path in std::iter::once(&group.root).chain(&group.import_paths) {
+                let path = std::path::absolute(path)?;
+                if !import_paths.contains(&path) {
+                    import_paths.push(path);
+                }
+            }
+        }
+        for path in &self.annotations_dirs {
+            let path = std::path::absolute(path)?;
+            if !import_paths.contains(&path) {
+                import_paths.push(path);
+            }
+        }
+        let import_paths = env::join_paths(&import_paths)
+            .context("an import path of the schema crate can't be passed on to dependents")?;
+        Ok(vec![
+            format!("cargo:capnp_import_dir={}", out_dir.display()),
+            format!(
+                "cargo:capnp_import_paths={}",
+                import_paths.to_string_lossy()
+            ),
+        ])
+    }
+
+    /// Compiles every matched schema into `dir` and writes `dir/capnp_include.rs`, returning its path, like
+    /// [`Config::build`] does for `OUT_DIR`. Nothing is told to cargo, so this works outside of build
+    /// scripts too, such as to generate the code of two revisions of a schema into separate directories
+    /// and diff them. The helper refers to the generated files by their absolute path.
+    pub fn generate_into(&self, dir: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+        self.generate_files_into(&dir)?;
+        Ok(dir.as_ref().join("capnp_include.rs"))
+    }
+
+    /// Like [`Config::generate_into`], but returns the paths of the files generated into `dir`, see
+    /// [`Config::emit_modules`].
+    ///
+    /// ```no_run
+    /// // Inside build.rs, for a tool that post-processes the generated code
+    /// let out_dir = std::env::var("OUT_DIR")?;
+    /// let files = capnp_import_core::Config::new()
+    ///     .pattern("schema/*.capnp")
+    ///     .emit_modules(false)
+    ///     .generate_files_into(&out_dir)?;
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn generate_files_into(&self, dir: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).with_context(|| format!("could not create {:?}", dir))?;
+        let helper_path = dir.join("capnp_include.rs");
+        let generated = if self.codegen_only {
+            // the generated files are the whole point, so they're always kept
+            let mut config = self.clone();
+            config.inline_mod = false;
+            config.single_file = false;
+            compile_schemas(&config, Some(dir))?
+                .1
+                .into_iter()
+                .map(|(file_path, _)| file_path)
+                .collect()
+        } else if self.append {
+            self.merge_into(&helper_path, dir)?
+        } else {
+            let (helperfile, generated) = process_files(self, Some(dir))?;
+            fs::write(&helper_path, helperfile)?;
+            generated
+        };
+        if self.inline_mod && !self.codegen_only {
+            // spliced into the helper, so there are no generated files left
+            return Ok(Vec::new());
+        }
+        Ok(generated
+            .into_iter()
+            .map(|file_path| dir.join(file_path))
+            .collect())
+    }
+
+    /// Writes the generated code into `dir` as ordinary source, to check into a crate: a file for every
+    /// module, and a `mod.rs` declaring them in every directory, so `mod proto;` with `dir` being
+    /// `src/proto` is all it takes to use them. With [`Config::nested`] the directories of the schemas
+    /// become subdirectories. The generated code refers to other schemas from the crate root, so set
+    /// [`Config::parent`] to the module `dir` is declared as, `proto` in that case. Returns the paths of the
+    /// files written, files already in `dir` are left alone. See [`Config::check_source_tree`] to keep it
+    /// up to date in CI.
+    ///
+    /// ```no_run
+    /// capnp_import_core::Config::new()
+    ///     .pattern("schema/*.capnp")
+    ///     .parent("proto")
+    ///     .generate_source_tree("src/proto")?;
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn generate_source_tree(&self, dir: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        let mut written = Vec::new();
+        for (path, code) in self.source_tree()? {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap_or(dir))
+                .with_context(|| format!("could not create {:?}", path.parent()))?;
+            fs::write(&path, code).with_context(|| format!("could not write {:?}", path))?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// Fails, naming the files, if the source tree in `dir` isn't what [`Config::generate_source_tree`]
+    /// would write now: when files are missing or out of date, or there are Rust files no schema generates
+    /// any more. Meant for CI, so code checked in with an earlier version of the schemas gets noticed.
+    pub fn check_source_tree(&self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dir = dir.as_ref();
+        let tree = self.source_tree()?;
+        let mut problems = Vec::new();
+        for (path, code) in &tree {
+            match fs::read_to_string(dir.join(path)) {
+                Ok(existing) if existing == *code => {}
+                Ok(_) => problems.push(format!("{:?} is out of date", dir.join(path))),
+                Err(_) => problems.push(format!("{:?} is missing", dir.join(path))),
+            }
+        }
+        for entry in walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(Result::ok)
+        {
+            let path = entry.path();
+            let stale = path.extension().is_some_and(|extension| extension == "rs")
+                && path
+                    .strip_prefix(dir)
+                    .is_ok_and(|relative| !tree.contains_key(relative));
+            if stale {
+                problems.push(format!("{:?} is not generated by any schema", path));
+            }
+        }
+        if !problems.is_empty() {
+            bail!(
+                "the generated source tree in {:?} is stale, regenerate it with Config::generate_source_tree:\n{}",
+                dir,
+                problems.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// The files of [`Config::generate_source_tree`], by their path relative to the tree.
+    fn source_tree(&self) -> anyhow::Result<BTreeMap<PathBuf, String>> {
+        let scratch = tempfile::tempdir()?;
+        let mut config = self.clone();
+        config.codegen_only = true;
+        config.inline_mod = false;
+        config.single_file = false;
+        config.incremental = false;
+        let (_, generated) = compile_schemas(&config, Some(scratch.path()))?;
+        let header = header_comment(self);
+        let visibility = self.visibility.as_deref().unwrap_or("pub");
+        let mut tree = BTreeMap::new();
+        let mut declarations: BTreeMap<PathBuf, BTreeSet<String>> = BTreeMap::new();
+        for (file_path, _) in generated {
+            let directories = match self.nested {
+                true => directory_modules(&file_path, self)?,
+                false => Vec::new(),
+            };
+            let name = module_name(&file_path)?;
+            let code = fs::read_to_string(scratch.path().join(&file_path)).with_context(|| {
+                format!("capnpc did not generate the expected file {:?}", file_path)
+            })?;
+            let module_dir: PathBuf = directories.iter().collect();
+            tree.insert(
+                module_dir.join(format!("{}.rs", name)),
+                header.clone() + &code,
+            );
+            declarations.entry(module_dir).or_default().insert(name);
+            for (i, directory) in directories.iter().enumerate() {
+                let parent: PathBuf = directories[..i].iter().collect();
+                declarations
+                    .entry(parent)
+                    .or_default()
+                    .insert(directory.clone());
+            }
+        }
+        for (module_dir, names) in declarations {
+            let mut code = header.clone();
+            for name in names {
+                if let Some(feature) = &self.feature {
+                    let _ = writeln!(code, "#[cfg(feature = {:?})]", feature);
+                }
+                if let Some(cfg) = self
+                    .cfg
+                    .as_ref()
+                    .filter(|_| module_dir.as_os_str().is_empty())
+                {
+                    let _ = writeln!(code, "#[cfg({})]", cfg);
+                }
+                for lint in &self.allows {
+                    let _ = writeln!(code, "#[allow({})]", lint);
+                }
+                let _ = writeln!(code, "{} mod {};", visibility, name);
+            }
+            tree.insert(module_dir.join("mod.rs"), code);
+        }
+        Ok(tree)
+    }
+
+    /// The helper the macro would expand to for this configuration, to inspect what it generates. The code is
+    /// kept in `$OUT_DIR` if that's set, as for the macro. With `CAPNP_IMPORT_PREVIEW` set in the environment
+    /// nothing is compiled, the helper is assembled from the files an earlier build left in `$OUT_DIR`.
+    ///
+    /// ```no_run
+    /// println!("{}", capnp_import_core::Config::new().pattern("schema/*.capnp").preview()?);
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn preview(&self) -> anyhow::Result<String> {
+        let out_dir = env::var_os("OUT_DIR").map(PathBuf::from);
+        if env::var_os("CAPNP_IMPORT_PREVIEW").is_none() {
+            return process_inner(self, out_dir.as_deref());
+        }
+        let out_dir = out_dir.context(
+            "previewing without compiling needs $OUT_DIR to point at the output of an earlier build",
+        )?;
+        let mut config = self.clone();
+        config.dry_run = true;
+        process_inner(&config, Some(&out_dir))
+    }
+
+    /// A fingerprint of the public API generated for every module, as lines of the module name and a hash, to
+    /// commit and compare in CI so that accidental breaking changes to schemas get noticed. Only the names and
+    /// signatures of public items count, so comments, formatting and the order of declarations don't change
+    /// it. The hash is stable across platforms and versions of Rust, though not necessarily across versions
+    /// of capnpc.
+    pub fn api_fingerprint(&self) -> anyhow::Result<String> {
+        let mut fingerprint = String::new();
+        for (name, code) in compile_modules(self, None)?.0 {
+            let mut surface = api_surface(&code);
+            surface.sort();
+            surface.dedup();
+            let _ = writeln!(
+                fingerprint,
+                "{} {:016x}",
+                name,
+                fnv1a(surface.join("\n").as_bytes())
+            );
+        }
+        Ok(fingerprint)
+    }
+
+    /// Every schema that would be compiled, sorted and without duplicates, without compiling anything.
+    pub fn matched_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for group in self.groups() {
+            files.extend(group.files(self.order)?);
+        }
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    /// Compiles the modules and merges them into the helper at `helper_path`, see [`Config::append`].
+    /// Returns the paths of the generated files, relative to `out_dir`.
+    fn merge_into(&self, helper_path: &Path, out_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let lock = fs::File::create(helper_path.with_extension("rs.lock"))?;
+        lock.lock().context("could not lock the helper")?;
+
+        let mut modules = BTreeMap::new();
+        if let Ok(helperfile) = fs::read_to_string(helper_path) {
+            for module in helperfile.split(MODULE_MARKER).skip(1) {
+                let (name, code) = module.split_once('\n').unwrap_or((module, ""));
+                modules.insert(name.to_owned(), code.to_owned());
+            }
+        }
+        let (compiled, generated) = compile_modules(self, Some(out_dir))?;
+        modules.extend(compiled);
+
+        let mut helperfile = header_comment(self);
+        for (name, code) in modules {
+            let _ = write!(helperfile, "{}{}\n{}", MODULE_MARKER, name, code);
+        }
+        fs::write(helper_path, helperfile)?;
+        Ok(generated)
+    }
+
+    /// Every group to compile, starting with one for each root the top level patterns are walked in.
+    fn groups(&self) -> Vec<Group> {
+        let mut groups = Vec::new();
+        if !self.patterns.is_empty() || !self.indexes.is_empty() {
+            let default_root = self.roots.is_empty().then(|| PathBuf::from("."));
+            for root in default_root.into_iter().chain(self.roots.iter().cloned()) {
+                // each root is also an import path, so `/base.capnp` resolves to the one in it
+                let import_paths = if self.roots.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![root.clone()]
+                };
+                groups.push(Group {
+                    root,
+                    patterns: self.patterns.clone(),
+                    indexes: self.indexes.clone(),
+                    import_paths,
+                    excludes: Vec::new(),
+                    extensions: Vec::new(),
+                    sniff: false,
+                });
+            }
+        }
+        groups.extend(self.groups.iter().cloned());
+        for group in &mut groups {
+            group.excludes.extend(self.excludes.iter().cloned());
+            group.extensions.extend(self.extensions.iter().cloned());
+            group.sniff |= self.sniff_schemas;
+        }
+        groups
+    }
+}
+
+/// The variable cargo passes `cargo:capnp_import_{what}` of the crate with the `links` key `links` on to the
+/// build scripts of its dependents as.
+fn schema_crate_var(links: &str, what: &str) -> String {
+    format!(
+        "DEP_{}_CAPNP_IMPORT_{}",
+        links.to_uppercase().replace('-', "_"),
+        what
+    )
+}
+
+/// The import paths the schema crate with the `links` key `links` passed on, see
+/// [`Config::import_schema_crate`].
+fn schema_crate_paths(links: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let var = schema_crate_var(links, "PATHS");
+    match env::var_os(&var) {
+        Some(paths) => Ok(env::split_paths(&paths).collect()),
+        None => bail!(
+            "{} is not set. The schema crate `{}` has to be a dependency of this crate, with `links = {:?}` in its package and a build script calling Config::build_schema_crate",
+            var,
+            links,
+            links
+        ),
+    }
+}
+
+/// `OUT_DIR`, which only build scripts are given.
+fn build_out_dir() -> anyhow::Result<PathBuf> {
+    let out_dir =
+        env::var("OUT_DIR").context("Config::build() must be called from a build script")?;
+    Ok(PathBuf::from(out_dir))
+}
+
+/// The minor version of the `capnp` runtime crate the generated code is written for, which is the one the
+/// bundled capnpc code generator targets.
+pub const CAPNP_RUNTIME: &str = "0.14";
+
+/// Precedes every module of a helper written by [`Config::append`], followed by the name of the module.
+const MODULE_MARKER: &str = "// capnp-import module: ";
+
+/// The annotations capnpc understands, such as `$Rust.name`, which schemas can always import as
+/// `/rust.capnp`.
+const RUST_ANNOTATIONS: &str = include_str!("../annotations/rust.capnp");
+
+/// Declares the module of a file capnpc generated, see [`Config::emitter`].
+pub trait Emitter {
+    /// The code declaring the module for `file_path`, relative to the `output_path` capnpc was given.
+    /// Other schemas refer to it as [`module_name`], so that's the name to keep for imports to resolve.
+    fn emit(&self, file_path: &Path, output_path: &Path) -> anyhow::Result<String>;
+}
+
+impl<F> Emitter for F
+where
+    F: Fn(&Path, &Path) -> anyhow::Result<String>,
+{
+    fn emit(&self, file_path: &Path, output_path: &Path) -> anyhow::Result<String> {
+        self(file_path, output_path)
+    }
+}
+
+/// The emitter set with [`Config::emitter`], if any.
+#[derive(Clone, Default)]
+struct CustomEmitter(Option<Arc<dyn Emitter + Send + Sync>>);
+
+impl std::fmt::Debug for CustomEmitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("CustomEmitter(..)"),
+            None => f.write_str("CustomEmitter(None)"),
+        }
+    }
+}
+
+/// Provides imports that aren't on disk up front, see [`Config::import_resolver`].
+pub trait ImportResolver {
+    /// The import paths to compile `schemas` with, in addition to the configured ones, after writing any
+    /// schemas that have to be materialized into `scratch`.
+    fn resolve(&self, schemas: &[PathBuf], scratch: &Path) -> anyhow::Result<Vec<PathBuf>>;
+}
+
+impl<F> ImportResolver for F
+where
+    F: Fn(&[PathBuf], &Path) -> anyhow::Result<Vec<PathBuf>>,
+{
+    fn resolve(&self, schemas: &[PathBuf], scratch: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        self(schemas, scratch)
+    }
+}
+
+/// The resolver set with [`Config::import_resolver`], if any.
+#[derive(Clone, Default)]
+struct CustomResolver(Option<Arc<dyn ImportResolver + Send + Sync>>);
+
+impl std::fmt::Debug for CustomResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("CustomResolver(..)"),
+            None => f.write_str("CustomResolver(None)"),
+        }
+    }
+}
+
+/// The comment at the top of the helper, if any.
+#[derive(Debug, Clone)]
+struct Header(Option<String>);
+
+impl Default for Header {
+    fn default() -> Self {
+        Self(Some(
+            "This file is autogenerated by capnp-import".to_owned(),
+        ))
+    }
+}
+
+/// The order generated modules are emitted in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// Sorted by the path of their schema relative to its root, regardless of which pattern matched it.
+    #[default]
+    Lexicographic,
+    /// Following the order of the groups and of the patterns within them, with the schemas matched by
+    /// each pattern sorted by path. Schemas matched by several patterns are emitted for the first one.
+    Input,
+    /// Like [`Order::Input`], except that the schemas matched by each pattern are left in the order the
+    /// filesystem walk found them in. That order depends on the filesystem, so builds may not be
+    /// reproducible. Only meant for code that relied on it before sorting became the default.
+    Discovery,
+}
+
+/// How a check reports a problem.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Ignores the problem.
+    Allow,
+    /// Prints a warning and carries on.
+    #[default]
+    Warn,
+    /// Fails the compilation.
+    Deny,
+}
+
+/// A code generator plugin, run like capnp runs the plugin of `capnp compile -o plugin:dir`: with the code
+/// generator request on its stdin and the directory the schemas are compiled into as its working directory,
+/// which is `OUT_DIR` for [`Config::build`].
+///
+/// ```no_run
+/// # use capnp_import_core::{Config, Plugin};
+/// Config::new()
+///     .pattern("schema/*.capnp")
+///     .plugin(Plugin::new("tools/capnpc-rust-fork").arg("--no-pointers").clone())
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Plugin {
+    path: PathBuf,
+    args: Vec<OsString>,
+}
+
+impl Plugin {
+    /// The plugin at `path`, relative to the crate root unless it's absolute. It has to exist when the
+    /// schemas are compiled.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Adds an argument to pass to the plugin.
+    pub fn arg(&mut self, arg: impl AsRef<std::ffi::OsStr>) -> &mut Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Runs the plugin on the code generator `request`, in `output_path`.
+    fn run(&self, request: &[u8], output_path: &Path) -> anyhow::Result<()> {
+        let path = std::path::absolute(&self.path)?;
+        let mut child = Command::new(&path)
+            .args(&self.args)
+            .current_dir(output_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("could not run the code generator plugin {:?}", path))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // a plugin that exits early closes its stdin, which its exit status explains better
+        let _ = io::Write::write_all(&mut stdin, request);
+        drop(stdin);
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "the code generator plugin {:?} exited with {}: {}",
+                path,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Schemas that are walked from the same root and share the same import paths.
+///
+/// ```no_run
+/// # use capnp_import_core::{Config, Group};
+/// Config::new()
+///     .group(Group::new("proto").pattern("**/*.capnp").clone())
+///     .group(Group::new("third_party/proto").pattern("**/*.capnp").clone())
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Group {
+    root: PathBuf,
+    patterns: Vec<String>,
+    indexes: Vec<PathBuf>,
+    import_paths: Vec<PathBuf>,
+    excludes: Vec<String>,
+    extensions: Vec<String>,
+    sniff: bool,
+}
+
+impl Group {
+    /// Creates a group walking `root`, which is also the first import path of its schemas.
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+            patterns: Vec::new(),
+            indexes: Vec::new(),
+            import_paths: vec![root.as_ref().to_owned()],
+            excludes: Vec::new(),
+            extensions: Vec::new(),
+            sniff: false,
+        }
+    }
+
+    /// Adds a glob pattern, matched against the paths below the root.
+    pub fn pattern(&mut self, pattern: impl AsRef<str>) -> &mut Self {
+        self.patterns.push(pattern.as_ref().to_owned());
+        self
+    }
+
+    /// Adds an index file listing schemas, one path per line relative to the root. See [`Config::index`].
+    pub fn index(&mut self, index: impl AsRef<Path>) -> &mut Self {
+        self.indexes.push(index.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a directory that absolute imports of this group's schemas are resolved against.
+    pub fn import_path(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.import_paths.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a glob pattern of schemas below the root to leave out. See [`Config::exclude`].
+    pub fn exclude(&mut self, pattern: impl AsRef<str>) -> &mut Self {
+        self.excludes.push(pattern.as_ref().to_owned());
+        self
+    }
+
+    /// The schemas matched by the patterns, followed by the ones listed in the indexes, without the
+    /// excluded ones and those without one of the extensions or, when sniffing, a file id.
+    fn files(&self, order: Order) -> anyhow::Result<Vec<PathBuf>> {
+        let mut files = find_files(&self.patterns, &self.root, order != Order::Discovery)?;
+        for index in &self.indexes {
+            for file in read_index(index, &self.root)? {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        for pattern in &self.excludes {
+            let glob = Glob::new(pattern)
+                .map_err(BuildError::into_owned)
+                .with_context(|| format!("invalid pattern `{}`", pattern))?;
+            files.retain(|file| !glob.is_match(file.strip_prefix(&self.root).unwrap_or(file)));
+        }
+        if !self.extensions.is_empty() || self.sniff {
+            files.retain(|file| {
+                let allowed = file.extension().is_some_and(|extension| {
+                    self.extensions
+                        .iter()
+                        .any(|allowed| extension == &**allowed)
+                });
+                allowed || self.sniff && declares_file_id(file)
+            });
+        }
+        Ok(files)
+    }
+}
+
+/// Walks `root` for files matching any of `patterns`, in pattern order and, if `sort` is set, sorted by
+/// path within each pattern. Every pattern has to be a valid glob that matches at least one file.
+fn find_files<I>(patterns: I, root: &Path, sort: bool) -> anyhow::Result<Vec<PathBuf>>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        // walked from the literal directories the pattern starts with, as wax::walk does
+        let (prefix, glob) = Glob::new(pattern)
+            .map_err(BuildError::into_owned)
+            .with_context(|| format!("invalid pattern `{}`", pattern))?
+            .partition();
+        let walked = root.join(prefix);
+
+        let first = files.len();
+        let mut matched = false;
+        for entry_result in glob.walk(&walked) {
+            let entry = match entry_result {
+                Ok(entry) => entry,
+                // a missing root just means nothing matched
+                Err(e) if e.depth() == 0 && e.path().is_some_and(|path| !path.exists()) => break,
+                // such as a directory without read permissions next to the schemas
+                Err(e) if !unreadable_match(&glob, &walked, e.path()) => {
+                    warn(&format!(
+                        "skipping {:?} while looking for `{}`: {}",
+                        e.path().unwrap_or(root),
+                        pattern,
+                        e
+                    ));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // the type the walk found, rather than another lookup of a file that may be gone or unreadable by
+            // now, except for links, which are matched by what they point to
+            let file_type = entry.file_type();
+            if file_type.is_file() || file_type.is_symlink() && entry.path().is_file() {
+                matched = true;
+                let entry = entry.into_path();
+                if !files.contains(&entry) {
+                    files.push(entry);
+                }
+            }
+        }
+        if !matched {
+            bail!(
+                "pattern `{}` did not match any files in {:?}",
+                pattern,
+                root
+            );
+        }
+        if sort {
+            files[first..].sort();
+        }
+    }
+    Ok(files)
+}
+
+/// Whether the entry at `path` that couldn't be read while walking `root` matches `glob`, which makes it an
+/// error rather than something to skip. Entries without a path, like the root itself, always match.
+fn unreadable_match(glob: &Glob, root: &Path, path: Option<&Path>) -> bool {
+    path.is_none_or(|path| path == root || glob.is_match(path.strip_prefix(root).unwrap_or(path)))
+}
+
+/// The schemas listed in `index`, relative to `root`. Fails with every listed schema that doesn't exist.
+fn read_index(index: &Path, root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let contents = fs::read_to_string(index)
+        .with_context(|| format!("could not read the index {:?}", index))?;
+    let files: Vec<PathBuf> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| root.join(line))
+        .collect();
+
+    let missing: Vec<String> = files
+        .iter()
+        .filter(|file| !file.is_file())
+        .map(|file| format!("{:?}", file))
+        .collect();
+    if !missing.is_empty() {
+        bail!(
+            "the index {:?} lists schemas that don't exist: {}",
+            index,
+            missing.join(", ")
+        );
+    }
+    Ok(files)
+}
+
+/// `schemas` without the ones that are empty, or only contain whitespace, as `severity` allows.
+fn skip_empty_files(schemas: Vec<PathBuf>, severity: Severity) -> anyhow::Result<Vec<PathBuf>> {
+    let mut kept = Vec::with_capacity(schemas.len());
+    for schema in schemas {
+        let contents = fs::read(&schema).with_context(|| format!("could not read {:?}", schema))?;
+        if !contents.iter().all(u8::is_ascii_whitespace) {
+            kept.push(schema);
+            continue;
+        }
+        let message = format!("schema {:?} is empty", schema);
+        match severity {
+            Severity::Deny => bail!(message),
+            Severity::Warn => warn(&format!("{}, skipping it", message)),
+            Severity::Allow => {}
+        }
+    }
+    Ok(kept)
+}
+
+/// Reports schemas that share a file id according to `severity`.
+fn check_file_ids<'a>(
+    schemas: impl IntoIterator<Item = &'a PathBuf>,
+    severity: Severity,
+) -> anyhow::Result<()> {
+    if severity == Severity::Allow {
+        return Ok(());
+    }
+    let mut seen = BTreeMap::new();
+    for schema in schemas {
+        let Some(id) = file_id(schema)? else {
+            continue;
+        };
+        let Some(first) = seen.insert(id, schema) else {
+            continue;
+        };
+        let message = format!(
+            "schemas {:?} and {:?} share the file id @0x{:016x}, generate a new one for either with `capnp id`",
+            first, schema, id
+        );
+        match severity {
+            Severity::Deny => bail!(message),
+            _ => warn(&message),
+        }
+    }
+    Ok(())
+}
+
+/// Reports schemas whose paths only differ in case according to `severity`.
+fn check_case_collisions<'a>(
+    schemas: impl IntoIterator<Item = &'a PathBuf>,
+    severity: Severity,
+) -> anyhow::Result<()> {
+    if severity == Severity::Allow {
+        return Ok(());
+    }
+    let mut seen: BTreeMap<String, &PathBuf> = BTreeMap::new();
+    for schema in schemas {
+        let folded = lexical_path(schema).to_string_lossy().to_lowercase();
+        let Some(first) = seen.insert(folded, schema) else {
+            continue;
+        };
+        if lexical_path(first) == lexical_path(schema) {
+            continue;
+        }
+        let message = format!(
+            "schemas {:?} and {:?} only differ in case, so they collide on case-insensitive filesystems like those of macOS and Windows. Rename either of them",
+            first, schema
+        );
+        match severity {
+            Severity::Deny => bail!(message),
+            _ => warn(&message),
+        }
+    }
+    Ok(())
+}
+
+/// Whether `file` declares a file id, see [`file_id`]. Files that can't be read don't.
+fn declares_file_id(file: &Path) -> bool {
+    matches!(file_id(file), Ok(Some(_)))
+}
+
+/// The `@0x...` id the first line of `schema` that isn't blank or a comment declares, if it declares one as
+/// capnp accepts it, 64 bits with the highest one set.
+fn file_id(schema: &Path) -> anyhow::Result<Option<u64>> {
+    let file = fs::File::open(schema).with_context(|| format!("could not read {:?}", schema))?;
+    let mut first_line = None;
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line.with_context(|| format!("could not read {:?}", schema))?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if !line.is_empty() {
+            first_line = Some(line.to_owned());
+            break;
+        }
+    }
+    Ok(first_line
+        .as_deref()
+        .and_then(|line| line.strip_prefix("@0x"))
+        .and_then(|line| line.strip_suffix(';'))
+        .map(str::trim)
+        .filter(|id| id.len() == 16 && id.chars().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|id| u64::from_str_radix(id, 16).ok())
+        .filter(|id| id >> 63 == 1))
+}
+
+/// Runs the `formatter` command on a generated `file`, reporting failures according to `severity`.
+fn run_formatter(formatter: &str, file: &Path, severity: Severity) -> anyhow::Result<()> {
+    let mut args = formatter.split_whitespace();
+    let program = args.next().context("the formatter command is empty")?;
+    let failure = match Command::new(program).args(args).arg(file).status() {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => format!("`{}` failed on {:?} with {}", formatter, file, status),
+        Err(e) => format!("could not run `{}`: {}", formatter, e),
+    };
+    match severity {
+        Severity::Allow => {}
+        Severity::Warn => warn(&failure),
+        Severity::Deny => bail!(failure),
+    }
+    Ok(())
+}
+
+/// Replaces the paths starting at the `capnp` crate that capnpc generates, with or without a leading `::`,
+/// with `capnp_crate`. Paths that merely go through a module called `capnp`, say for a struct of that name,
+/// are left alone.
+fn rewrite_capnp_crate(code: &str, capnp_crate: &str) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let mut rewritten = String::with_capacity(code.len());
+    let mut last = 0;
+    for (at, path) in code.match_indices("capnp::") {
+        let preceding = &code[..at];
+        let start = match preceding.strip_suffix("::") {
+            Some(parent) if parent.ends_with(is_ident) => continue,
+            Some(_) => at - 2,
+            None if preceding.ends_with(is_ident) => continue,
+            None => at,
+        };
+        rewritten += &code[last..start];
+        rewritten += capnp_crate;
+        rewritten += "::";
+        last = at + path.len();
+    }
+    rewritten += &code[last..];
+    rewritten
+}
+
+/// The lines of the first comment block of a schema, before any of its declarations. A comment after
+/// the file id on the same line is not part of it.
+fn schema_docs(schema: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = fs::read(schema).with_context(|| format!("could not read {:?}", schema))?;
+    let mut docs = Vec::new();
+    for line in String::from_utf8_lossy(&contents).lines() {
+        let line = line.trim();
+        match line.strip_prefix('#') {
+            Some(comment) => docs.push(comment.strip_prefix(' ').unwrap_or(comment).to_owned()),
+            None if docs.is_empty() && (line.is_empty() || line.starts_with("@0x")) => {}
+            None => break,
+        }
+    }
+    Ok(docs)
+}
+
+/// Prints a warning through cargo when running in a build script, otherwise to the compiler's output.
+fn warn(message: &str) {
+    // cargo only sets these for build scripts
+    if env::var_os("TARGET").is_some() && env::var_os("HOST").is_some() {
+        println!("cargo:warning={}", message);
+    } else {
+        eprintln!("warning: {}", message);
+    }
+}
+
+/// Where capnpc writes the code for a schema, relative to its output directory. Like capnpc, this drops
+/// whatever extension the schema has, so `foo.capnproto` ends up in `foo_capnp.rs` too.
+fn generated_path(schema: &Path) -> anyhow::Result<PathBuf> {
+    let stem = file_stem(schema)?;
+    Ok(schema.with_file_name(format!("{}_capnp.rs", stem.replace('-', "_"))))
+}
+
+/// Compiles all the schemas matched by `config` and returns a helper wrapping each of them in a module.
+/// With an `out_dir` the generated code is kept there and `include!`d, otherwise it is compiled into a
+/// temporary directory and spliced in. This is what both the `capnp_import!` macro and [`Config::build`]
+/// run, exposed for tools that want the helper without either.
+pub fn process_inner(config: &Config, out_dir: Option<&Path>) -> anyhow::Result<String> {
+    Ok(process_files(config, out_dir)?.0)
+}
+
+/// Like [`process_inner`], but also returns the paths of the generated files, relative to `out_dir`.
+fn process_files(
+    config: &Config,
+    out_dir: Option<&Path>,
+) -> anyhow::Result<(String, Vec<PathBuf>)> {
+    let header = header_comment(config);
+    let (modules, generated) = compile_modules(config, out_dir)?;
+    let size = modules
+        .iter()
+        .map(|(_, module)| module.len())
+        .sum::<usize>();
+    check_helper_size(config, out_dir, size)?;
+    let mut helperfile = String::with_capacity(header.len() + size);
+    helperfile += &header;
+    if let Some(out_dir) = out_dir.filter(|_| config.single_file && !config.inline_mod) {
+        // named after what it holds, so invocations compiling different schemas don't clash
+        let mut merged = String::with_capacity(size);
+        for (_, module) in modules {
+            merged += &module;
+        }
+        let merged_path =
+            out_dir.join(format!("capnp_merged_{:016x}.rs", fnv1a(merged.as_bytes())));
+        fs::write(&merged_path, merged)
+            .with_context(|| format!("could not write {:?}", merged_path))?;
+        let _ = writeln!(helperfile, "include!({});", include_path(&merged_path)?);
+        return Ok((helperfile, generated));
+    }
+    for (_, module) in modules {
+        helperfile += &module;
+    }
+    Ok((helperfile, generated))
+}
+
+/// Code beyond this size isn't spliced into a single file, rustc would take gigabytes of memory to parse it
+/// in one go. Generated code that large is `include!`d from its own file instead.
+const MAX_HELPER_SIZE: usize = 64 * 1024 * 1024;
+
+/// Fails with advice fitting the mode `config` is used in if `size` bytes of modules are too much for one
+/// file, see [`MAX_HELPER_SIZE`].
+fn check_helper_size(config: &Config, out_dir: Option<&Path>, size: usize) -> anyhow::Result<()> {
+    if size <= MAX_HELPER_SIZE {
+        return Ok(());
+    }
+    let advice = match out_dir {
+        None => "Add a build script so it's `include!`d from OUT_DIR instead",
+        Some(_) if config.inline_mod => "Turn off `inline_mod` so it's `include!`d instead",
+        Some(_) => {
+            "Turn off `single_file` so every schema is `include!`d from a file of its own instead"
+        }
+    };
+    bail!(
+        "the generated code is {} MiB, too much for a single file. {}",
+        size / 1024 / 1024,
+        advice
+    );
+}
+
+/// The schemas `patterns` match below the crate root, as the `capnp_import!` macro would compile them,
+/// sorted and without duplicates. Nothing is compiled, so this is a cheap way to check a set of globs.
+pub fn matched_files(patterns: &[&str]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut config = Config::new();
+    for pattern in patterns {
+        config.pattern(pattern);
+    }
+    config.matched_files()
+}
+
+/// The directory of the package called `name` in the workspace of the crate being built, to use as a root
+/// instead of a path like `../../other-crate/proto`. The workspace is found from `CARGO_MANIFEST_DIR`, so this
+/// works from build scripts and the macro, and fails naming the packages there are if there's no such
+/// package.
+///
+/// ```no_run
+/// // Inside build.rs
+/// capnp_import_core::Config::new()
+///     .pattern("proto/*.capnp")
+///     .root(capnp_import_core::package_dir("protos")?)
+///     .build()?;
+/// # anyhow::Ok(())
+/// ```
+pub fn package_dir(name: &str) -> anyhow::Result<PathBuf> {
+    let crate_root =
+        env::var_os("CARGO_MANIFEST_DIR").map_or_else(|| PathBuf::from("."), PathBuf::from);
+    find_package(&std::path::absolute(crate_root)?, name)
+}
+
+/// The directory of the package called `name` in the workspace of the crate at `crate_root`.
+fn find_package(crate_root: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let read_manifest = |dir: &Path| -> anyhow::Result<toml::Table> {
+        let manifest = dir.join("Cargo.toml");
+        let contents = fs::read_to_string(&manifest)
+            .with_context(|| format!("could not read {:?}", manifest))?;
+        toml::from_str(&contents).with_context(|| format!("could not parse {:?}", manifest))
+    };
+    let package_name = |manifest: &toml::Table| {
+        manifest
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(toml::Value::as_str)
+            .map(str::to_owned)
+    };
+    if package_name(&read_manifest(crate_root)?).as_deref() == Some(name) {
+        return Ok(crate_root.to_owned());
+    }
+
+    let Some((workspace_root, workspace)) = crate_root
+        .ancestors()
+        .filter(|dir| dir.join("Cargo.toml").is_file())
+        .filter_map(|dir| Some((dir, read_manifest(dir).ok()?.get("workspace")?.clone())))
+        .next()
+    else {
+        bail!(
+            "there is no package `{}`, as {:?} isn't part of a workspace",
+            name,
+            crate_root
+        );
+    };
+    let members = workspace
+        .get("members")
+        .and_then(toml::Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(toml::Value::as_str);
+    let mut packages = Vec::new();
+    for member in members {
+        let walk = wax::walk(member, workspace_root)
+            .map_err(BuildError::into_owned)
+            .with_context(|| format!("invalid workspace member `{}`", member))?;
+        for entry in walk.flatten() {
+            let dir = entry.into_path();
+            if !dir.join("Cargo.toml").is_file() {
+                continue;
+            }
+            match package_name(&read_manifest(&dir)?) {
+                Some(package) if package == name => return Ok(dir),
+                Some(package) => packages.push(package),
+                None => {}
+            }
+        }
+    }
+    bail!(
+        "there is no package `{}` in the workspace at {:?}, only {}",
+        name,
+        workspace_root,
+        packages
+            .iter()
+            .map(|package| format!("`{}`", package))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// The helper the macro would expand to for the schemas `patterns` match below the crate root, as
+/// [`Config::preview`] assembles it.
+pub fn preview(patterns: &[&str]) -> anyhow::Result<String> {
+    let mut config = Config::new();
+    for pattern in patterns {
+        config.pattern(pattern);
+    }
+    config.preview()
+}
+
+/// The fingerprint of the API generated for the schemas `patterns` match below the crate root, see
+/// [`Config::api_fingerprint`].
+///
+/// ```no_run
+/// // Inside a test
+/// let fingerprint = capnp_import_core::generated_api_fingerprint(&["schema/*.capnp"])?;
+/// assert_eq!(fingerprint, std::fs::read_to_string("schema/api-fingerprint.txt")?);
+/// # anyhow::Ok(())
+/// ```
+pub fn generated_api_fingerprint(patterns: &[&str]) -> anyhow::Result<String> {
+    let mut config = Config::new();
+    for pattern in patterns {
+        config.pattern(pattern);
+    }
+    config.api_fingerprint()
+}
+
+/// The public items of the generated `code`, each prefixed with the modules and impls it's declared in.
+/// Lines of `pub enum`s and `pub struct`s count too, as variants and fields aren't marked `pub`, while the
+/// `_private` modules capnpc generates and the bodies of functions don't. Relies on capnpc putting every
+/// declaration on a line of its own and indenting blocks consistently.
+fn api_surface(code: &str) -> Vec<String> {
+    let mut surface = Vec::new();
+    // the indentation and first line of every block the current line is in
+    let mut blocks: Vec<(usize, &str)> = Vec::new();
+    for line in code.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with("//") || trimmed.starts_with("#[") {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        while blocks.last().is_some_and(|&(outer, _)| outer >= indent) {
+            blocks.pop();
+        }
+        let private = trimmed.starts_with("pub mod _private")
+            || blocks.iter().any(|(_, block)| {
+                block.starts_with("pub mod _private") || block.starts_with("fn ")
+            });
+        let innermost = blocks.last().map_or("", |&(_, block)| block);
+        let public = trimmed.starts_with("pub ")
+            || innermost.starts_with("pub enum ")
+            || innermost.starts_with("pub struct ");
+        if public && !private && !trimmed.starts_with('}') {
+            let mut item = String::new();
+            for (_, block) in &blocks {
+                if block.starts_with("pub mod ") || block.starts_with("impl") {
+                    item += block.trim_end_matches('{').trim_end();
+                    item += " / ";
+                }
+            }
+            item += trimmed.trim_end_matches('{').trim_end();
+            surface.push(item);
+        }
+        if trimmed.ends_with('{') {
+            blocks.push((indent, trimmed));
+        }
+    }
+    surface
+}
+
+/// `path` without any `.` components, so `./proto/a.capnp` is known to be inside `proto`.
+fn lexical_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != std::path::Component::CurDir)
+        .collect()
+}
+
+/// The import path `file` is in, other than `root`, if any. capnp names schemas inside an import path after
+/// their path below it, as that's how absolute imports refer to them, and the longest source prefix wins.
+fn import_root<'a>(file: &Path, import_paths: &'a [PathBuf], root: &Path) -> Option<&'a PathBuf> {
+    import_paths
+        .iter()
+        .filter(|dir| lexical_path(file).starts_with(lexical_path(dir)))
+        .max_by_key(|dir| lexical_path(dir).components().count())
+        .filter(|dir| lexical_path(dir) != lexical_path(root))
+}
+
+/// The deepest directory below `root` holding all of the schemas in `files` that aren't in one of the
+/// `import_paths`, if that isn't `root` itself. capnp can't resolve relative imports reaching out of the
+/// source prefix, so it holds the files those import too.
+fn default_src_prefix(files: &[PathBuf], import_paths: &[PathBuf], root: &Path) -> Option<PathBuf> {
+    let schemas = files
+        .iter()
+        .filter(|file| import_root(file, import_paths, root).is_none());
+    let relative = with_imports(schemas, &[]).into_iter().filter_map(|file| {
+        let relative = lexical_path(&file)
+            .strip_prefix(lexical_path(root))
+            .ok()?
+            .to_owned();
+        // ones reaching out of the root are generated relative to it anyway
+        let outside = relative
+            .components()
+            .any(|component| component == std::path::Component::ParentDir);
+        (!outside).then_some(relative)
+    });
+    common_dir(relative).map(|dir| root.join(dir))
+}
+
+/// `schemas` and every file they import, directly or through each other, without `..`. Absolute imports are
+/// resolved against the first of `import_paths` that has them, those that resolve to no file are left out.
+fn with_imports<'a>(
+    schemas: impl IntoIterator<Item = &'a PathBuf>,
+    import_paths: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut pending: Vec<PathBuf> = schemas.into_iter().cloned().collect();
+    while let Some(file) = pending.pop() {
+        let mut resolved = PathBuf::new();
+        for component in file.components() {
+            match component {
+                std::path::Component::ParentDir if resolved.file_name().is_some() => {
+                    resolved.pop();
+                }
+                std::path::Component::CurDir => {}
+                component => resolved.push(component),
+            }
+        }
+        if files.contains(&resolved) {
+            continue;
+        }
+        // unreadable schemas are reported when compiling them
+        if let Ok(contents) = fs::read(&resolved) {
+            let dir = resolved.parent().unwrap_or(Path::new(""));
+            for import in schema_imports(&String::from_utf8_lossy(&contents)) {
+                match import.strip_prefix('/') {
+                    Some(absolute) => pending.extend(
+                        import_paths
+                            .iter()
+                            .map(|import_path| import_path.join(absolute))
+                            .find(|file| file.is_file()),
+                    ),
+                    None => pending.push(dir.join(import)),
+                }
+            }
+        }
+        files.push(resolved);
+    }
+    files
+}
+
+/// The deepest directory all of `files` are in, if there are any and it isn't the one they're relative to.
+fn common_dir(files: impl IntoIterator<Item = PathBuf>) -> Option<PathBuf> {
+    let mut common: Option<Vec<OsString>> = None;
+    for file in files {
+        let dir: Vec<_> = file
+            .parent()
+            .unwrap_or(Path::new(""))
+            .components()
+            .map(|component| component.as_os_str().to_owned())
+            .collect();
+        common = Some(match common {
+            None => dir,
+            Some(common) => common
+                .into_iter()
+                .zip(dir)
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    common
+        .filter(|common| !common.is_empty())
+        .map(|common| common.into_iter().collect())
+}
+
+/// Where the copy of `path` goes below `mirror`, at its absolute path with `..` resolved like
+/// [`with_imports`] does, so the copies import each other just like the originals.
+fn mirror_path(mirror: &Path, path: &Path) -> PathBuf {
+    let mut copy = mirror.to_owned();
+    for component in path.components() {
+        match component {
+            std::path::Component::ParentDir if copy != mirror => {
+                copy.pop();
+            }
+            std::path::Component::Normal(name) => copy.push(name),
+            _ => {}
+        }
+    }
+    copy
+}
+
+/// `schema` with every `{{KEY}}` replaced by its value from `config`, along with the keys that have none.
+/// Braces around anything but a key are left alone.
+fn substitute(schema: &str, config: &Config) -> (String, Vec<String>) {
+    let mut substituted = String::new();
+    let mut undefined = Vec::new();
+    let mut rest = schema;
+    while let Some(start) = rest.find("{{") {
+        substituted += &rest[..start];
+        rest = &rest[start..];
+        let Some(end) = rest.find("}}") else {
+            break;
+        };
+        let key = rest[2..end].trim();
+        let is_key = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_');
+        let value = match config.substitutions.get(key) {
+            _ if !is_key => None,
+            Some(value) => Some(value.clone()),
+            None if config.substitute_env => {
+                if env::var_os("TARGET").is_some() && env::var_os("HOST").is_some() {
+                    println!("cargo:rerun-if-env-changed={}", key);
+                }
+                env::var(key).ok()
+            }
+            None => None,
+        };
+        match value {
+            Some(value) => {
+                substituted += &value;
+                rest = &rest[end + 2..];
+            }
+            None => {
+                if is_key && !undefined.iter().any(|undefined| undefined == key) {
+                    undefined.push(key.to_owned());
+                }
+                substituted += "{{";
+                rest = &rest[2..];
+            }
+        }
+    }
+    substituted += rest;
+    (substituted, undefined)
+}
+
+/// The 64 bit FNV-1a hash of `bytes`, which unlike [`std::hash::DefaultHasher`] is the same everywhere.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// A [`Hasher`] computing [`fnv1a`], for hashes that are kept across builds and so across toolchains.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Compiles the schemas `patterns` match below the crate root into `dir`, as [`Config::generate_into`] does,
+/// and returns the path of the helper written there.
+pub fn generate_into(patterns: &[&str], dir: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let mut config = Config::new();
+    for pattern in patterns {
+        config.pattern(pattern);
+    }
+    config.generate_into(dir)
+}
+
+/// Compiles the schema read from `reader` as if it were a file called `name`, such as `example.capnp`, and
+/// returns a module named after it containing the generated code. Only a temporary copy of the schema
+/// touches the filesystem, which suits scripts and tests that produce schemas on the fly.
+///
+/// ```no_run
+/// let code = capnp_import_core::compile_reader("example.capnp", std::io::stdin())?;
+/// assert!(code.starts_with("pub mod example_capnp {"));
+/// # anyhow::Ok(())
+/// ```
+pub fn compile_reader(name: &str, mut reader: impl Read) -> anyhow::Result<String> {
+    let file_name = Path::new(name);
+    if file_name.file_name() != Some(file_name.as_os_str()) {
+        bail!("{:?} is not a plain file name", name);
+    }
+    let schemas = tempfile::tempdir()?;
+    let mut schema = fs::File::create(schemas.path().join(file_name))?;
+    io::copy(&mut reader, &mut schema).context("could not read the schema")?;
+    drop(schema);
+
+    let mut config = Config::new();
+    config.pattern(wax::escape(name)).root(schemas.path());
+    let (modules, _) = compile_modules(&config, None)?;
+    Ok(modules.into_iter().map(|(_, module)| module).collect())
+}
+
+/// The comment at the top of the helper.
+fn header_comment(config: &Config) -> String {
+    let mut comment = String::new();
+    if let Some(header) = &config.header.0 {
+        for line in header.lines() {
+            let _ = writeln!(comment, "{}", format!("// {}", line).trim_end());
+        }
+    }
+    comment
+}
+
+/// Compiles all the schemas matched by `config`, returning the path of the code generated for each of them
+/// and the schema itself. The paths are relative to `out_dir`, or to the returned scratch directory when the
+/// code isn't kept in `out_dir`.
+fn compile_schemas(
+    config: &Config,
+    out_dir: Option<&Path>,
+) -> anyhow::Result<(tempfile::TempDir, Vec<(PathBuf, PathBuf)>)> {
+    if let Some(runtime) = &config.runtime {
+        let supported = runtime
+            .strip_prefix(CAPNP_RUNTIME)
+            .is_some_and(|patch| patch.is_empty() || patch.starts_with('.'));
+        if !supported {
+            bail!(
+                "the generated code targets version {} of the `capnp` crate, not {}. Depend on capnp {} or use a version of capnp-import that targets {}",
+                CAPNP_RUNTIME,
+                runtime,
+                CAPNP_RUNTIME,
+                runtime
+            );
+        }
+    }
+    if let Some(parent) = &config.parent {
+        if !is_identifier(parent) {
+            bail!("the parent module `{}` is not an identifier", parent);
+        }
+    }
+    if let Some(cfg) = &config.cfg {
+        let mut depth = 0i32;
+        for c in cfg.chars() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            if depth < 0 {
+                break;
+            }
+        }
+        if cfg.is_empty() || depth != 0 {
+            bail!("`{}` is not a cfg predicate", cfg);
+        }
+    }
+    if config.nested && config.src_prefix.is_some() {
+        bail!("src_prefix only applies to flat modules, use strip_prefix to shorten nested module paths");
+    }
+    if let Some(plugin) = &config.plugin {
+        if !plugin.path.is_file() {
+            bail!("the code generator plugin {:?} does not exist", plugin.path);
+        }
+    }
+    let mut crate_import_paths = Vec::new();
+    for links in &config.schema_crates {
+        crate_import_paths.extend(schema_crate_paths(links)?);
+    }
+    let embedded_capnp = !cfg!(feature = "docs-only") && config.compiler.is_none();
+    let cache_dir = env::var_os("CAPNP_IMPORT_CACHE_DIR").filter(|_| embedded_capnp);
+    let output_dir = match cache_dir {
+        _ if config.compiler.is_some() => scratchdir(),
+        Some(_) => scratchdir(),
+        None => commandhandle(),
+    }
+    .context("could not create temporary capnp binary")?;
+    let mut cmdpath = match (&config.compiler, &cache_dir) {
+        (Some(compiler), _) => compiler.clone(),
+        (None, Some(cache_dir)) => cached_capnp(Path::new(cache_dir))?,
+        (None, None) => output_dir.path().join("capnp"),
+    };
+    let embedded = cmdpath.clone();
+    if embedded_capnp {
+        cmdpath = verify_capnp(cmdpath)?;
+    }
+    // a system capnp stood in for the embedded one, whose hash says nothing about it
+    let compiler_hash =
+        (config.compiler.is_none() && cmdpath == embedded).then_some(CAPNP_BINARY_HASH);
+    let rust_annotations = output_dir.path().join("annotations");
+    fs::create_dir(&rust_annotations)?;
+    fs::write(rust_annotations.join("rust.capnp"), RUST_ANNOTATIONS)?;
+    let resolve_output =
+        |path: &PathBuf| out_dir.map_or_else(|| path.clone(), |out_dir| out_dir.join(path));
+    let manifest_path = config.manifest.as_ref().map(resolve_output);
+    let depfile_path = config.depfile.as_ref().map(resolve_output);
+    let out_dir = match out_dir {
+        Some(out_dir) if !config.inline_mod => Some(out_dir),
+        _ => None,
+    };
+
+    let incremental = out_dir.filter(|_| config.incremental);
+    let mut state = incremental
+        .map(|out_dir| read_state(out_dir, config))
+        .unwrap_or_default();
+    let mut pending = Vec::new();
+
+    // overlapping roots find the same schemas, which are compiled from the first root finding them
+    let mut seen = BTreeSet::new();
+    let groups = config
+        .groups()
+        .into_iter()
+        .map(|group| {
+            let mut files = skip_empty_files(group.files(config.order)?, config.empty_files)?;
+            files.retain(|file| {
+                seen.insert(fs::canonicalize(file).unwrap_or_else(|_| lexical_path(file)))
+            });
+            Ok((group, files))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let all_files = groups.iter().flat_map(|(_, files)| files);
+    check_file_ids(all_files.clone(), config.duplicate_ids)?;
+    check_case_collisions(all_files, config.case_collisions)?;
+
+    let mut generated = Vec::new();
+    let mut skipped = Vec::new();
+    let mut passed_import_paths: Vec<PathBuf> = Vec::new();
+    let mut imports = BTreeSet::new();
+    let mut dependencies = BTreeMap::new();
+    for (i, (group, files)) in groups.into_iter().enumerate() {
+        let output_path = out_dir.unwrap_or(output_dir.path());
+        let root = group.root.as_path();
+        let mut import_paths = group.import_paths.clone();
+        import_paths.extend(config.annotations_dirs.iter().cloned());
+        import_paths.extend(crate_import_paths.iter().cloned());
+        if let Some(resolver) = config
+            .import_resolver
+            .0
+            .as_ref()
+            .filter(|_| !files.is_empty())
+        {
+            let scratch = output_dir.path().join(format!("imports-{}", i));
+            fs::create_dir(&scratch)?;
+            let resolved = resolver
+                .resolve(&files, &scratch)
+                .context("the import resolver failed")?;
+            import_paths.extend(resolved);
+        }
+        if depfile_path.is_some() {
+            let search_paths: Vec<PathBuf> = import_paths
+                .iter()
+                .cloned()
+                .chain(CAPNP_INCLUDE_DIR.map(PathBuf::from))
+                .collect();
+            for file in &files {
+                // scratch files, such as those of an import resolver, are gone by the time anything reads it
+                let mut prerequisites = with_imports([file], &search_paths);
+                prerequisites.retain(|prerequisite| !prerequisite.starts_with(output_dir.path()));
+                dependencies.insert(file.clone(), prerequisites);
+            }
+        }
+        // templated schemas are compiled from a copy, at the same path below it so relative imports resolve
+        let mirror = (!config.substitutions.is_empty() || config.substitute_env)
+            .then(|| output_dir.path().join(format!("templated-{}", i)));
+        let mirrored = |path: &Path| match &mirror {
+            Some(mirror) => mirror_path(mirror, path),
+            None => path.to_owned(),
+        };
+        if let Some(mirror) = &mirror {
+            let mut undefined = Vec::new();
+            for file in with_imports(&files, &[]) {
+                let Ok(contents) = fs::read(&file) else {
+                    continue;
+                };
+                let copy = mirror_path(mirror, &file);
+                fs::create_dir_all(copy.parent().unwrap_or(mirror))?;
+                // the files they import are copied as they are
+                let matched = files
+                    .iter()
+                    .any(|matched| mirror_path(mirror, matched) == copy);
+                let contents = if matched {
+                    let (contents, missing) =
+                        substitute(&String::from_utf8_lossy(&contents), config);
+                    undefined.extend(missing.into_iter().map(|key| (key, file.clone())));
+                    contents.into_bytes()
+                } else {
+                    contents
+                };
+                fs::write(&copy, contents)?;
+            }
+            if !undefined.is_empty() {
+                bail!(
+                    "no value to substitute for {}",
+                    undefined
+                        .iter()
+                        .map(|(key, file)| format!("`{{{{{}}}}}` in {:?}", key, file))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        if config.check_imports {
+            let search_paths: Vec<PathBuf> = import_paths
+                .iter()
+                .cloned()
+                .chain([rust_annotations.clone()])
+                .chain(CAPNP_INCLUDE_DIR.map(PathBuf::from))
+                .collect();
+            check_imports(&files, &search_paths)?;
+        }
+        // nested modules mirror the directories below the root, so only flat ones are generated below a prefix
+        let src_prefix = match &config.src_prefix {
+            _ if config.nested => None,
+            Some(prefix) => Some(root.join(prefix)),
+            None => default_src_prefix(&files, &import_paths, root),
+        };
+        // capnpc refers to the module of each schema in the code it generates, so with nested modules
+        // every directory is compiled on its own, with the modules it's nested in
+        let mut batches: Vec<(Vec<String>, Vec<PathBuf>)> = Vec::new();
+        for file in files {
+            let mut parent_module: Vec<String> = config.parent.iter().cloned().collect();
+            match file.strip_prefix(root) {
+                Ok(relative) if config.nested => {
+                    parent_module.extend(directory_modules(relative, config)?)
+                }
+                _ => {}
+            }
+            match batches
+                .iter_mut()
+                .find(|(parent, _)| *parent == parent_module)
+            {
+                Some((_, batch)) => batch.push(file),
+                None => batches.push((parent_module, vec![file])),
+            }
+        }
+
+        for (parent_module, files) in batches {
+            let mut cmd = CapnpCompile::new(&cmdpath, output_path, config.verbose);
+            cmd.parent_module(parent_module);
+            cmd.deny_warnings(config.deny_warnings);
+            cmd.plugin(config.plugin.as_ref());
+            for import_path in &import_paths {
+                cmd.import_path(import_path);
+            }
+            cmd.import_path(&rust_annotations);
+            if let Some(include_dir) = CAPNP_INCLUDE_DIR {
+                cmd.import_path(include_dir);
+            }
+            let passed = import_paths
+                .iter()
+                .cloned()
+                .chain([rust_annotations.clone()])
+                .chain(CAPNP_INCLUDE_DIR.map(PathBuf::from));
+            for import_path in passed {
+                if !passed_import_paths.contains(&import_path) {
+                    passed_import_paths.push(import_path);
+                }
+            }
+
+            cmd.src_prefix(&mirrored(root));
+            if let Some(prefix) = &src_prefix {
+                cmd.src_prefix(&mirrored(prefix));
+            }
+            let mut compiled = Vec::new();
+            let mut sources = Vec::new();
+            for file in files {
+                let relative = match import_root(&file, &import_paths, root) {
+                    Some(dir) => {
+                        cmd.src_prefix(&mirrored(dir));
+                        lexical_path(&file)
+                            .strip_prefix(lexical_path(dir))?
+                            .to_owned()
+                    }
+                    None => match file.strip_prefix(src_prefix.as_deref().unwrap_or(root)) {
+                        Ok(relative) => relative.to_owned(),
+                        Err(_) if file.starts_with(root) => file.strip_prefix(root)?.to_owned(),
+                        // rooted patterns can match outside of the root, those are generated next to each other
+                        Err(_) => {
+                            cmd.src_prefix(&mirrored(file.parent().unwrap_or(root)));
+                            PathBuf::from(file.file_name().unwrap_or_default())
+                        }
+                    },
+                };
+                let generated_file = generated_path(&relative)?;
+                // a dry run shows the code of an earlier build
+                if cfg!(feature = "docs-only") || config.dry_run {
+                    generated.push((generated_file, file));
+                    continue;
+                }
+                let source = mirrored(&file);
+                if let Some(out_dir) = incremental {
+                    let hash = content_hash(&source)?;
+                    let key = file.to_string_lossy().into_owned();
+                    let unchanged = state.get(&key) == Some(&hash);
+                    if unchanged && out_dir.join(&generated_file).is_file() {
+                        generated.push((generated_file, file));
+                        continue;
+                    }
+                    // only recorded once the code is generated, so a failed run compiles it again
+                    pending.push((key, hash));
+                }
+                cmd.file(&source);
+                compiled.push((output_path.join(&generated_file), source.clone()));
+                sources.push((source, file.clone()));
+                generated.push((generated_file, file));
+            }
+            if !compiled.is_empty() {
+                let schemas: Vec<PathBuf> =
+                    compiled.iter().map(|(_, schema)| schema.clone()).collect();
+                let mut requests = Vec::new();
+                let mut failed = Vec::new();
+                // thousands of schemas don't fit on one command line, but every batch resolves imports alike
+                for batch in cmd.batches(&schemas) {
+                    match cmd.only(batch, &schemas).run() {
+                        Ok(request) => requests.push(request),
+                        Err(_) if config.best_effort => {
+                            for schema in batch {
+                                match cmd.only(std::slice::from_ref(schema), &schemas).run() {
+                                    Ok(request) => requests.push(request),
+                                    Err(e) => {
+                                        warn(&format!(
+                                            "skipping {:?}, which failed to compile: {:#}",
+                                            schema, e
+                                        ));
+                                        failed.push(schema.clone());
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            return Err(e.context("capnp failed to compile the matched schemas"))
+                        }
+                    }
+                }
+                compiled.retain(|(_, schema)| !failed.contains(schema));
+                skipped.extend(
+                    sources
+                        .iter()
+                        .filter(|(source, _)| failed.contains(source))
+                        .map(|(_, file)| file.clone()),
+                );
+                if manifest_path.is_some() {
+                    for request in &requests {
+                        imports.extend(request_imports(request)?);
+                    }
+                }
+                if config.serde_shims {
+                    for request in &requests {
+                        append_serde_shims(request, output_path, &compiled)?;
+                    }
+                }
+                if config.rpc_wrappers {
+                    for request in &requests {
+                        let wrappers = rpc_wrappers::rpc_wrappers(request)?;
+                        append_generated(wrappers, output_path, &compiled, "the RPC wrappers")?;
+                    }
+                }
+            }
+            if config.codec_helpers {
+                for (file, schema) in &compiled {
+                    if let Some(helpers) = codec_helpers(schema)? {
+                        fs::OpenOptions::new()
+                            .append(true)
+                            .open(file)
+                            .and_then(|mut file| {
+                                io::Write::write_all(&mut file, helpers.as_bytes())
+                            })
+                            .with_context(|| {
+                                format!("could not add codec helpers to {:?}", file)
+                            })?;
+                    }
+                }
+            }
+            if let Some(capnp_crate) = &config.capnp_crate {
+                for (file, _) in &compiled {
+                    let code = fs::read_to_string(file).with_context(|| {
+                        format!("capnpc did not generate the expected file {:?}", file)
+                    })?;
+                    fs::write(file, rewrite_capnp_crate(&code, capnp_crate))?;
+                }
+            }
+            if let Some(formatter) = &config.formatter {
+                for (file, _) in &compiled {
+                    run_formatter(formatter, file, config.formatter_failures)?;
+                }
+            }
+        }
+    }
+    generated.retain(|(_, schema)| !skipped.contains(schema));
+    state.extend(pending);
+    for schema in &skipped {
+        state.remove(schema.to_string_lossy().as_ref());
+    }
+
+    // stripping a prefix can easily make two schemas end up in the same module
+    if let Some(out_dir) = out_dir {
+        let outputs = generated
+            .iter()
+            .map(|(file_path, _)| out_dir.join(file_path));
+        for output in register_outputs(outputs, config) {
+            warn(&format!(
+                "{:?} is also generated by another invocation with options that change the generated code, so which of them it ends up holding is unspecified. Compile each schema only once, or from distinct roots",
+                output
+            ));
+        }
+    }
+    let matched = (config.strict || config.strip_prefix.is_some()).then(|| generated.clone());
+    if config.order == Order::Lexicographic {
+        generated.sort();
+        generated.dedup_by(|a, b| a.0 == b.0);
+    }
+    if let Some(matched) = matched {
+        check_modules(&matched, &generated, config)?;
+    }
+    if let Some(path) = depfile_path {
+        let targets = generated.iter().map(|(file_path, schema)| {
+            let target =
+                out_dir.map_or_else(|| file_path.clone(), |out_dir| out_dir.join(file_path));
+            (
+                target,
+                dependencies.get(schema).cloned().unwrap_or_default(),
+            )
+        });
+        fs::write(&path, depfile(targets))
+            .with_context(|| format!("could not write {:?}", path))?;
+    }
+    if let Some(path) = manifest_path {
+        let manifest = manifest_json(
+            &cmdpath,
+            compiler_hash,
+            &passed_import_paths,
+            &generated,
+            &imports,
+        );
+        fs::write(&path, manifest).with_context(|| format!("could not write {:?}", path))?;
+    }
+    if let Some(out_dir) = incremental {
+        write_state(out_dir, config, &state)?;
+    }
+    Ok((output_dir, generated))
+}
+
+/// The name and code of each module compiled.
+type Modules = Vec<(String, String)>;
+
+/// Compiles all the schemas matched by `config` into the name and code of a module for each of them, along
+/// with the paths of the generated files, relative to `out_dir` when they're kept there.
+fn compile_modules(
+    config: &Config,
+    out_dir: Option<&Path>,
+) -> anyhow::Result<(Modules, Vec<PathBuf>)> {
+    let (output_dir, generated) = compile_schemas(config, out_dir)?;
+    let generated_paths = generated
+        .iter()
+        .map(|(file_path, _)| file_path.clone())
+        .collect();
+    let out_dir = out_dir.filter(|_| !config.inline_mod);
+
+    let mut generated_src = String::new();
+    if config.generated_src {
+        for (file_path, schema) in &generated {
+            if cfg!(feature = "docs-only") {
+                generated_src += &stub_code(schema)?;
+                continue;
+            }
+            let file = out_dir.unwrap_or(output_dir.path()).join(file_path);
+            generated_src += &fs::read_to_string(&file)
+                .with_context(|| format!("capnpc did not generate the expected file {:?}", file))?;
+        }
+    }
+
+    let mut modules = Vec::new();
+    let mut tree = ModuleTree::default();
+    let mut unexposed: Vec<&str> = config.exposed.iter().map(String::as_str).collect();
+    for (file_path, schema) in generated {
+        let mut module = String::new();
+        if config.lift_docs {
+            for line in schema_docs(&schema)? {
+                let _ = writeln!(module, "/// {}", line);
+            }
+        }
+        let visibility = match &config.visibility {
+            Some(visibility) => visibility,
+            None if config.exposed.is_empty() => "pub",
+            None => "pub(crate)",
+        };
+        let code = match out_dir {
+            _ if cfg!(feature = "docs-only") => stub_module(&file_path, &schema, visibility)?,
+            Some(out_dir) if config.single_file => {
+                append_contents(&file_path, out_dir, visibility)?
+            }
+            Some(out_dir) => match &config.emitter.0 {
+                Some(emitter) => emitter.emit(&file_path, out_dir)?,
+                None if config.path_mod => append_path_mod(&file_path, out_dir, visibility)?,
+                None => append_path(&file_path, out_dir, visibility)?,
+            },
+            None => append_contents(&file_path, output_dir.path(), visibility)?,
+        };
+        if let Some(feature) = &config.feature {
+            let _ = writeln!(module, "#[cfg(feature = {:?})]", feature);
+        }
+        if let Some(cfg) = config.cfg.as_ref().filter(|_| config.parent.is_none()) {
+            let _ = writeln!(module, "#[cfg({})]", cfg);
+        }
+        for lint in &config.allows {
+            let _ = writeln!(module, "#[allow({})]", lint);
+        }
+        if !config.rustfmt_generated {
+            module += "#[rustfmt::skip]\n";
+        }
+        module += &code;
+        if !config.exposed.is_empty() {
+            let name = module_name(&file_path)?;
+            let contents =
+                fs::read(&schema).with_context(|| format!("could not read {:?}", schema))?;
+            for (kind, type_name, _) in schema_types(&String::from_utf8_lossy(&contents)) {
+                if !config.exposed.iter().any(|exposed| exposed == type_name) {
+                    continue;
+                }
+                unexposed.retain(|exposed| *exposed != type_name);
+                let item = match kind {
+                    "enum" => type_name.to_owned(),
+                    _ => type_module_name(type_name),
+                };
+                let _ = writeln!(module, "pub use {}::{};", name, item);
+            }
+        }
+        if config.nested {
+            tree.insert(
+                &directory_modules(&file_path, config)?,
+                module_name(&file_path)?,
+                module,
+            );
+        } else {
+            modules.push((module_name(&file_path)?, module));
+        }
+    }
+    if !unexposed.is_empty() {
+        bail!(
+            "no compiled schema declares {} at the top level, so they can't be exposed",
+            unexposed
+                .iter()
+                .map(|name| format!("`{}`", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if config.nested {
+        modules = tree.into_modules();
+    }
+    if config.generated_src {
+        // escaped by Debug, which writes a valid string literal for any text
+        modules.push((
+            "GENERATED_SRC".to_owned(),
+            format!("pub const GENERATED_SRC: &str = {:?};\n", generated_src),
+        ));
+    }
+    let reexports = reexports(config, &modules)?;
+    if let Some(parent) = &config.parent {
+        let mut code = String::new();
+        if let Some(cfg) = &config.cfg {
+            let _ = writeln!(code, "#[cfg({})]", cfg);
+        }
+        let _ = writeln!(code, "pub mod {} {{", parent);
+        for (i, (name, module)) in modules.iter().enumerate() {
+            if modules[..i].iter().any(|(other, _)| other == name) {
+                bail!(
+                    "several schemas would be the module `{}::{}`, rename all but one of them",
+                    parent,
+                    name
+                );
+            }
+            code += module;
+        }
+        modules = vec![(parent.clone(), code + "}\n")];
+    }
+    modules.extend(reexports);
+    Ok((modules, generated_paths))
+    // When TempDir goes out of scope, it gets deleted
+}
+
+/// The `use` declaration for each of the [`Config::reexport`]s of `config`, checked against the `modules`
+/// they re-export from.
+fn reexports(
+    config: &Config,
+    modules: &[(String, String)],
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut reexports: Vec<(String, String)> = Vec::new();
+    for (path, name) in &config.reexports {
+        // capnpc 0.14 predates the keywords of the 2018 edition, so they aren't in KEYWORDS
+        let keyword = KEYWORDS
+            .iter()
+            .chain(&["async", "await", "dyn"])
+            .any(|keyword| keyword == name);
+        if !is_identifier(name) || keyword {
+            bail!(
+                "can't re-export `{}` as `{}`, which is not an identifier",
+                path,
+                name
+            );
+        }
+        if !path.split("::").all(is_identifier) {
+            bail!("can't re-export `{}`, which is not a path", path);
+        }
+        let module = path.split("::").next().unwrap_or_default();
+        if !modules.iter().any(|(other, _)| other == module) {
+            bail!(
+                "can't re-export `{}`, no schema was compiled into the module `{}`",
+                path,
+                module
+            );
+        }
+        let taken = modules
+            .iter()
+            .chain(&reexports)
+            .any(|(other, _)| other == name);
+        if taken {
+            bail!(
+                "can't re-export `{}` as `{}`, that name is already taken by a module or another re-export",
+                path,
+                name
+            );
+        }
+        let mut code = String::new();
+        if let Some(feature) = &config.feature {
+            let _ = writeln!(code, "#[cfg(feature = {:?})]", feature);
+        }
+        if let Some(cfg) = &config.cfg {
+            let _ = writeln!(code, "#[cfg({})]", cfg);
+        }
+        let path = match &config.parent {
+            Some(parent) => format!("{}::{}", parent, path),
+            None => path.clone(),
+        };
+        let _ = writeln!(code, "pub(crate) use {} as {};", path, name);
+        reexports.push((name.clone(), code));
+    }
+    Ok(reexports)
+}
+
+/// Whether `name` can name a module or type, leaving keywords aside.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// How long the arguments of a `capnp compile` run may get, leaving room for the executable and the
+/// environment below the 32767 characters Windows allows.
+const MAX_COMMAND_LINE: usize = 24 * 1024;
+
+/// A `capnp compile` run feeding capnpc's code generator, like `capnpc::CompilerCommand`, except that the
+/// diagnostics of capnp are captured so they end up in the error instead of being lost in the
+/// compiler's output.
+#[derive(Clone)]
+struct CapnpCompile<'a> {
+    executable: &'a Path,
+    output_path: &'a Path,
+    verbose: bool,
+    deny_warnings: bool,
+    parent_module: Vec<String>,
+    plugin: Option<&'a Plugin>,
+    args: Vec<OsString>,
+}
+
+impl<'a> CapnpCompile<'a> {
+    fn new(executable: &'a Path, output_path: &'a Path, verbose: bool) -> Self {
+        Self {
+            executable,
+            output_path,
+            verbose,
+            deny_warnings: false,
+            parent_module: Vec::new(),
+            plugin: None,
+            args: Vec::new(),
+        }
+    }
+
+    /// Generates the code with `plugin` instead of capnpc.
+    fn plugin(&mut self, plugin: Option<&'a Plugin>) {
+        self.plugin = plugin;
+    }
+
+    /// Fails the compilation when capnp reports any warnings.
+    fn deny_warnings(&mut self, deny_warnings: bool) {
+        self.deny_warnings = deny_warnings;
+    }
+
+    /// The module the generated modules are nested in, relative to the crate root.
+    fn parent_module(&mut self, parent_module: Vec<String>) {
+        self.parent_module = parent_module;
+    }
+
+    fn import_path(&mut self, dir: impl AsRef<Path>) {
+        let mut arg = OsString::from("--import-path=");
+        arg.push(dir.as_ref());
+        self.args.push(arg);
+    }
+
+    fn src_prefix(&mut self, dir: &Path) {
+        let mut arg = OsString::from("--src-prefix=");
+        arg.push(dir);
+        self.args.push(arg);
+    }
+
+    fn file(&mut self, file: &Path) {
+        self.args.push(file.into());
+    }
+
+    /// A copy of this run that only compiles `kept` out of the `files` added to it.
+    fn only(&self, kept: &[PathBuf], files: &[PathBuf]) -> Self {
+        let kept: BTreeSet<&Path> = kept.iter().map(PathBuf::as_path).collect();
+        let files: BTreeSet<&Path> = files.iter().map(PathBuf::as_path).collect();
+        let mut only = self.clone();
+        only.args
+            .retain(|arg| kept.contains(Path::new(arg)) || !files.contains(Path::new(arg)));
+        only
+    }
+
+    /// The `files` added to this run, split into batches that each fit on a command line along with the
+    /// other arguments. That's 32767 characters on Windows, other platforms allow more.
+    fn batches<'f>(&self, files: &'f [PathBuf]) -> Vec<&'f [PathBuf]> {
+        let length = |arg: &std::ffi::OsStr| arg.len() + 1;
+        let fixed: usize = self
+            .only(&[], files)
+            .args
+            .iter()
+            .map(|arg| length(arg))
+            .sum();
+        let mut batches = Vec::new();
+        let mut start = 0;
+        let mut used = fixed;
+        for (i, file) in files.iter().enumerate() {
+            if i > start && used + length(file.as_os_str()) > MAX_COMMAND_LINE {
+                batches.push(&files[start..i]);
+                start = i;
+                used = fixed;
+            }
+            used += length(file.as_os_str());
+        }
+        if start < files.len() {
+            batches.push(&files[start..]);
+        }
+        batches
+    }
+
+    /// Compiles the schemas, returning the code generator request capnp sent.
+    fn run(&self) -> anyhow::Result<Vec<u8>> {
+        let mut command = Command::new(self.executable);
+        command.args(["compile", "-o", "-"]);
+        if self.verbose {
+            command.arg("--verbose");
+        }
+        // capnp warns whenever $PWD is stale, as it is under cargo, and falls back to the real directory anyway.
+        // Stand-ins for capnp, see Config::compiler, are told where the code goes
+        command
+            .args(&self.args)
+            .env_remove("PWD")
+            .env("CAPNP_IMPORT_OUTPUT_DIR", self.output_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            // the binary was just extracted with execute permissions, so it's the directory that forbids it
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => bail!(
+                "could not run {:?}: {}. Its directory is probably on a filesystem mounted noexec, set $CAPNP_IMPORT_TMPDIR to a directory that allows executing files",
+                self.executable,
+                e
+            ),
+            Err(e) => {
+                return Err(e).with_context(|| format!("could not run {:?}", self.executable))
+            }
+        };
+
+        // capnp blocks once either pipe is full, so stderr is drained while the code generator reads stdout
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let diagnostics = std::thread::spawn(move || {
+            let mut diagnostics = String::new();
+            let _ = stderr.read_to_string(&mut diagnostics);
+            diagnostics
+        });
+        let mut request = Vec::new();
+        let read = child
+            .stdout
+            .take()
+            .expect("stdout is piped")
+            .read_to_end(&mut request);
+        let generated = read
+            .map_err(anyhow::Error::from)
+            .and_then(|_| match self.plugin {
+                Some(plugin) => plugin.run(&request, self.output_path),
+                None => Ok(capnpc::codegen::CodeGenerationCommand::new()
+                    .output_directory(self.output_path)
+                    .default_parent_module(self.parent_module.clone())
+                    .run(&request[..])?),
+            });
+        let status = child.wait()?;
+        let diagnostics = diagnostics.join().unwrap_or_default();
+        let diagnostics = diagnostics.trim_end();
+
+        let warnings: Vec<&str> = diagnostics
+            .lines()
+            .filter(|line| is_warning(line))
+            .collect();
+        if self.verbose {
+            for line in diagnostics.lines() {
+                warn(line);
+            }
+        } else if !self.deny_warnings {
+            for line in &warnings {
+                warn(line);
+            }
+        }
+        if !status.success() {
+            let error = match diagnostics {
+                "" => anyhow!("capnp exited with {}", status),
+                diagnostics => anyhow!("{}", diagnostics),
+            };
+            return Err(if self.verbose {
+                error.context(format!("{:?} exited with {}", command, status))
+            } else {
+                error
+            });
+        }
+        if self.deny_warnings && !warnings.is_empty() {
+            bail!(
+                "capnp reported warnings, which are denied:\n{}",
+                warnings.join("\n")
+            );
+        }
+        generated.context("capnpc could not generate code for the compiled schemas")?;
+        Ok(request)
+    }
+}
+
+/// Appends the `serde_shims` module of every schema in the code generator `request` to its generated
+/// file among `compiled`, which capnpc wrote below `output_path`.
+#[cfg(feature = "serde")]
+fn append_serde_shims(
+    request: &[u8],
+    output_path: &Path,
+    compiled: &[(PathBuf, PathBuf)],
+) -> anyhow::Result<()> {
+    let shims = serde_shims::serde_shims(request)?;
+    append_generated(shims, output_path, compiled, "the serde shims")
+}
+
+#[cfg(not(feature = "serde"))]
+fn append_serde_shims(_: &[u8], _: &Path, _: &[(PathBuf, PathBuf)]) -> anyhow::Result<()> {
+    bail!("serde_shims needs the `serde` feature of capnp-import")
+}
+
+/// Appends the `code` for each file, by the name capnp was given it under, to the file generated for it, if
+/// that was just compiled. `what` names the code in errors.
+fn append_generated(
+    code: Vec<(String, String)>,
+    output_path: &Path,
+    compiled: &[(PathBuf, PathBuf)],
+    what: &str,
+) -> anyhow::Result<()> {
+    for (file_name, code) in code {
+        let file = output_path.join(generated_path(Path::new(&file_name))?);
+        if !compiled.iter().any(|(compiled, _)| *compiled == file) {
+            continue;
+        }
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&file)
+            .and_then(|mut file| io::Write::write_all(&mut file, code.as_bytes()))
+            .with_context(|| format!("could not add {} to {:?}", what, file))?;
+    }
+    Ok(())
+}
+
+/// The files the schemas requested in the code generator `request` import, directly or through each other,
+/// under the names capnp gave them.
+fn request_imports(request: &[u8]) -> anyhow::Result<Vec<String>> {
+    let mut options = capnp::message::ReaderOptions::new();
+    options.traversal_limit_in_words(None);
+    let message = capnp::serialize::read_message(request, options)?;
+    let request: capnpc::schema_capnp::code_generator_request::Reader = message.get_root()?;
+    let requested = request
+        .get_requested_files()?
+        .iter()
+        .map(|file| file.get_id())
+        .collect::<Vec<_>>();
+    let mut imports = Vec::new();
+    for node in request.get_nodes()?.iter() {
+        if let Ok(capnpc::schema_capnp::node::File(())) = node.which() {
+            if !requested.contains(&node.get_id()) {
+                imports.push(node.get_display_name()?.to_owned());
+            }
+        }
+    }
+    Ok(imports)
+}
+
+/// The manifest written by [`Config::manifest`] for the schemas compiled by `compiler` with `import_paths`.
+fn manifest_json(
+    compiler: &Path,
+    compiler_hash: Option<&str>,
+    import_paths: &[PathBuf],
+    generated: &[(PathBuf, PathBuf)],
+    imports: &BTreeSet<String>,
+) -> String {
+    let strings = |items: &mut dyn Iterator<Item = String>| {
+        items
+            .map(|item| json_string(&item))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let schemas = generated
+        .iter()
+        .map(|(generated, schema)| {
+            format!(
+                "{{\"schema\": {}, \"generated\": {}}}",
+                json_string(&schema.to_string_lossy()),
+                json_string(&generated.to_string_lossy())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n    ");
+    format!(
+        "{{\n  \"compiler\": {},\n  \"compiler_version\": {},\n  \"compiler_sha256\": {},\n  \"capnp_import_core\": {},\n  \"import_paths\": [{}],\n  \"schemas\": [\n    {}\n  ],\n  \"imports\": [{}]\n}}\n",
+        json_string(&compiler.to_string_lossy()),
+        json_string(CAPNP_VERSION),
+        compiler_hash.map_or_else(|| "null".to_owned(), json_string),
+        json_string(env!("CARGO_PKG_VERSION")),
+        strings(&mut import_paths.iter().map(|path| path.to_string_lossy().into_owned())),
+        schemas,
+        strings(&mut imports.iter().cloned())
+    )
+}
+
+/// The Make rules for `targets`, each with its prerequisites, and an empty rule for every prerequisite.
+fn depfile(targets: impl IntoIterator<Item = (PathBuf, Vec<PathBuf>)>) -> String {
+    // Make splits words on spaces, ends targets with :, starts comments with # and expands $. Windows
+    // paths get forward slashes, which Make and Ninja read there just as well
+    let escape = |path: &Path| {
+        let path = path.to_string_lossy();
+        let path = if cfg!(windows) {
+            path.replace('\\', "/")
+        } else {
+            path.into_owned()
+        };
+        let mut escaped = String::new();
+        for c in path.chars() {
+            match c {
+                ' ' | '#' | ':' => escaped.extend(['\\', c]),
+                '$' => escaped.push_str("$$"),
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    };
+    let mut rules = String::new();
+    let mut prerequisites = BTreeSet::new();
+    for (target, dependencies) in targets {
+        rules += &escape(&target);
+        rules.push(':');
+        for dependency in dependencies {
+            rules += " \\\n  ";
+            rules += &escape(&dependency);
+            prerequisites.insert(escape(&dependency));
+        }
+        rules.push('\n');
+    }
+    for prerequisite in prerequisites {
+        let _ = write!(rules, "\n{}:\n", prerequisite);
+    }
+    rules
+}
+
+/// `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c < ' ' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Whether `line` of what capnp reported is a warning, such as `file.capnp:3:1: warning: ...` or
+/// `kj/file.c++:96: warning: ...`, rather than an error or informational output.
+fn is_warning(line: &str) -> bool {
+    line.split_once(": warning: ")
+        .is_some_and(|(location, _)| location.ends_with(|c: char| c.is_ascii_digit()))
+}
+
+/// The outcome of checking the version of the embedded capnp, which is the same binary for every invocation
+/// of the macro in a compilation.
+static VERSION_CHECK: OnceLock<Result<(), String>> = OnceLock::new();
+
+/// Checks that the freshly extracted `executable` runs, and once per compilation that it's the version of
+/// capnp this crate was built with, so code is never generated by a broken or unexpected compiler. An
+/// extracted binary that doesn't run is replaced by the capnp on the `PATH`, if that's the same version,
+/// which is the executable returned.
+fn verify_capnp(executable: PathBuf) -> anyhow::Result<PathBuf> {
+    let reported = match run_extracted(&executable) {
+        Ok(reported) => reported,
+        Err(e) => return fall_back(e, which::which("capnp").ok()),
+    };
+    VERSION_CHECK
+        .get_or_init(|| check_version(&executable, &reported).map_err(|e| format!("{:#}", e)))
+        .clone()
+        .map_err(|e| anyhow!(e))?;
+    Ok(executable)
+}
+
+/// Falls back to the `system` capnp when the extracted one failed with `error`, as long as it's the version
+/// this crate was built with, warning that it's used.
+fn fall_back(error: anyhow::Error, system: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    let Some(system) = system else {
+        return Err(error.context(format!(
+            "there is no system capnp {} to fall back to either",
+            CAPNP_VERSION
+        )));
+    };
+    let reported = Command::new(&system)
+        .arg("--version")
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+                + &String::from_utf8_lossy(&output.stderr)
+        })
+        .unwrap_or_default();
+    if let Err(mismatch) = check_version(&system, &reported) {
+        return Err(error.context(format!(
+            "the system capnp can't be used instead: {:#}",
+            mismatch
+        )));
+    }
+    warn(&format!(
+        "falling back to {:?}, as the extracted capnp binary can't be used: {:#}",
+        system, error
+    ));
+    Ok(system)
+}
+
+/// The embedded binary extracted into `cache_dir` under a name unique to its version and contents, so that
+/// builds share it instead of extracting it every time, while crates built with other binaries don't.
+fn cached_capnp(cache_dir: &Path) -> anyhow::Result<PathBuf> {
+    let path = cache_dir.join(format!(
+        "capnp-{}-{:016x}{}",
+        CAPNP_VERSION,
+        fnv1a(CAPNP_EXECUTABLE),
+        env::consts::EXE_SUFFIX
+    ));
+    // it could have been truncated or tampered with since, so only an identical binary is reused
+    if fs::read(&path).is_ok_and(|existing| existing == CAPNP_EXECUTABLE) {
+        return Ok(path);
+    }
+    fs::create_dir_all(cache_dir)
+        .with_context(|| format!("could not create $CAPNP_IMPORT_CACHE_DIR ({:?})", cache_dir))?;
+    // extracted under a name of its own and renamed into place, so concurrent builds never run half a binary
+    let partial_dir = tempfile::Builder::new()
+        .prefix(".capnp-")
+        .tempdir_in(cache_dir)
+        .with_context(|| format!("could not extract capnp into {:?}", cache_dir))?;
+    let partial = partial_dir.path().join("capnp");
+    write_executable(&partial)?;
+    if let Err(e) = fs::rename(&partial, &path) {
+        // another build renaming its copy first is fine, Windows refuses to replace a running binary
+        if !fs::read(&path).is_ok_and(|existing| existing == CAPNP_EXECUTABLE) {
+            return Err(e).with_context(|| format!("could not extract capnp to {:?}", path));
+        }
+    }
+    Ok(path)
+}
+
+/// Runs `executable --version`, returning what it printed. Failing to is reported as a problem with the
+/// extracted binary, such as one truncated by a full disk, before any schema is blamed for it.
+fn run_extracted(executable: &Path) -> anyhow::Result<String> {
+    let output = Command::new(executable)
+        .arg("--version")
+        .output()
+        .with_context(|| {
+            format!(
+                "the extracted capnp binary {:?} could not be run, so extracting it failed or it is corrupt. This is a problem with the toolchain, not the schemas",
+                executable
+            )
+        })?;
+    if !output.status.success() {
+        bail!(
+            "the extracted capnp binary {:?} failed to report its version ({}), so it is likely corrupt. This is a problem with the toolchain, not the schemas: {}",
+            executable,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Fails if `executable` reported a version other than [`capnp_version`]. The version line is looked for
+/// among everything `reported`, as wrapper scripts can print more around it.
+fn check_version(executable: &Path, reported: &str) -> anyhow::Result<()> {
+    let version = reported.lines().find_map(|line| {
+        line.split_once("Cap'n Proto version ")
+            .map(|(_, version)| version.trim())
+    });
+    if version != Some(CAPNP_VERSION) {
+        bail!(
+            "{:?} reports {:?}, but capnp-import-core was built with capnp {} ({}). Rebuild capnp-import-core to pick up the new compiler",
+            executable,
+            reported.trim(),
+            CAPNP_VERSION,
+            CAPNP_SOURCE
+        );
+    }
+    Ok(())
+}
+
+/// The files each configuration generated into `OUT_DIR` during this compilation, as a hash of the
+/// configuration by path. The macro is expanded in a single compiler process, so this spans invocations.
+static OUTPUTS: Mutex<BTreeMap<PathBuf, u64>> = Mutex::new(BTreeMap::new());
+
+/// A hash of the options of `config` that change the code generated for a schema. The state file of
+/// incremental runs records it, so it has to stay the same across toolchains.
+fn codegen_hash(config: &Config) -> u64 {
+    let mut hasher = Fnv1a::default();
+    config.capnp_crate.hash(&mut hasher);
+    config.formatter.hash(&mut hasher);
+    config.annotations_dirs.hash(&mut hasher);
+    config.schema_crates.hash(&mut hasher);
+    config.nested.hash(&mut hasher);
+    config.strip_prefix.hash(&mut hasher);
+    config
+        .plugin
+        .as_ref()
+        .map(|plugin| (&plugin.path, &plugin.args))
+        .hash(&mut hasher);
+    config.parent.hash(&mut hasher);
+    config.codec_helpers.hash(&mut hasher);
+    config.serde_shims.hash(&mut hasher);
+    config.rpc_wrappers.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Records `outputs` as generated by `config`, returning the ones another configuration generated too.
+/// Only the options that change the generated code are compared, the rest can't conflict.
+fn register_outputs(outputs: impl IntoIterator<Item = PathBuf>, config: &Config) -> Vec<PathBuf> {
+    let config_hash = codegen_hash(config);
+
+    let mut registry = OUTPUTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut conflicts = Vec::new();
+    for output in outputs {
+        match registry.insert(output.clone(), config_hash) {
+            Some(previous) if previous != config_hash => conflicts.push(output),
+            _ => {}
+        }
+    }
+    conflicts
+}
+
+/// Checks that every schema in `matched` is `emitted` as a module of its own, both being the generated
+/// file and the schema for each module.
+fn check_modules(
+    matched: &[(PathBuf, PathBuf)],
+    emitted: &[(PathBuf, PathBuf)],
+    config: &Config,
+) -> anyhow::Result<()> {
+    let module_path = |file_path: &Path| -> anyhow::Result<String> {
+        let mut path = match config.nested {
+            true => directory_modules(file_path, config)?,
+            false => Vec::new(),
+        };
+        path.push(module_name(file_path)?);
+        Ok(path.join("::"))
+    };
+    let mut modules = BTreeMap::new();
+    for (file_path, schema) in emitted {
+        let module = module_path(file_path)?;
+        if let Some(first) = modules.insert(module.clone(), schema) {
+            bail!(
+                "schemas {:?} and {:?} are both emitted as the module `{}`",
+                first,
+                schema,
+                module
+            );
+        }
+    }
+    for (file_path, schema) in matched {
+        let module = module_path(file_path)?;
+        match modules.get(&module) {
+            Some(emitted) if *emitted == schema => {}
+            Some(emitted) => bail!(
+                "schema {:?} was dropped, the module `{}` was emitted for {:?} instead",
+                schema,
+                module,
+                emitted
+            ),
+            None => bail!("no module was emitted for schema {:?}", schema),
+        }
+    }
+    Ok(())
+}
+
+/// The modules a schema at `path`, relative to its root, is nested in, one for each directory below the
+/// [`Config::strip_prefix`].
+fn directory_modules(path: &Path, config: &Config) -> anyhow::Result<Vec<String>> {
+    let path = match &config.strip_prefix {
+        Some(prefix) => path.strip_prefix(prefix).unwrap_or(path),
+        None => path,
+    };
+    let parent = path.parent().unwrap_or(Path::new(""));
+    parent
+        .components()
+        .map(|component| {
+            let name = component.as_os_str().to_str().ok_or_else(|| {
+                anyhow!(
+                    "the directory name of {} is not valid UTF-8, so no module name can be derived from it",
+                    path.to_string_lossy()
+                )
+            })?;
+            Ok(name.replace('-', "_"))
+        })
+        .collect()
+}
+
+/// Modules nested in modules for their directories, in the order they were first inserted.
+#[derive(Default)]
+struct ModuleTree(Vec<(String, ModuleNode)>);
+
+enum ModuleNode {
+    Module(String),
+    Directory(ModuleTree),
+}
+
+impl ModuleTree {
+    fn insert(&mut self, directories: &[String], name: String, module: String) {
+        let Some((directory, rest)) = directories.split_first() else {
+            self.0.push((name, ModuleNode::Module(module)));
+            return;
+        };
+        let position = self.0.iter().position(|(existing, node)| {
+            existing == directory && matches!(node, ModuleNode::Directory(_))
+        });
+        let position = position.unwrap_or_else(|| {
+            self.0.push((
+                directory.clone(),
+                ModuleNode::Directory(ModuleTree::default()),
+            ));
+            self.0.len() - 1
+        });
+        if let ModuleNode::Directory(tree) = &mut self.0[position].1 {
+            tree.insert(rest, name, module);
+        }
+    }
+
+    /// The name and code of every top level module.
+    fn into_modules(self) -> Vec<(String, String)> {
+        self.0
+            .into_iter()
+            .map(|(name, node)| {
+                let code = match node {
+                    ModuleNode::Module(module) => module,
+                    ModuleNode::Directory(tree) => {
+                        let mut code = format!("pub mod {} {{\n", name);
+                        for (_, module) in tree.into_modules() {
+                            code += &module;
+                        }
+                        code + "}\n"
+                    }
+                };
+                (name, code)
+            })
+            .collect()
+    }
+}
+
+/// Where incremental runs keep the content hashes of the schemas they compiled, relative to `OUT_DIR`.
+const STATE_FILE: &str = "capnp_import_state";
+
+/// The compilers the code is generated with and a hash of the options changing it, which the state file
+/// starts with. Code generated by other versions may not match the runtime, and code generated with other
+/// options doesn't reflect the current ones, so a state file recorded with either is discarded.
+fn state_header(config: &Config) -> String {
+    format!(
+        "# compiled with capnp {} and capnp-import-core {}, options {:016x}",
+        CAPNP_VERSION,
+        env!("CARGO_PKG_VERSION"),
+        codegen_hash(config)
+    )
+}
+
+/// The content hash of every schema compiled into `out_dir` with `config`, by path. A missing or unreadable
+/// state file, or one recorded with other compilers or options, just means everything gets compiled.
+fn read_state(out_dir: &Path, config: &Config) -> BTreeMap<String, u64> {
+    let contents = fs::read_to_string(out_dir.join(STATE_FILE)).unwrap_or_default();
+    let mut lines = contents.lines();
+    if lines.next() != Some(state_header(config).as_str()) {
+        return BTreeMap::new();
+    }
+    lines
+        .filter_map(|line| {
+            let (hash, path) = line.split_once(' ')?;
+            Some((path.to_owned(), u64::from_str_radix(hash, 16).ok()?))
+        })
+        .collect()
+}
+
+fn write_state(
+    out_dir: &Path,
+    config: &Config,
+    state: &BTreeMap<String, u64>,
+) -> anyhow::Result<()> {
+    let mut contents = state_header(config) + "\n";
+    for (path, hash) in state {
+        let _ = writeln!(contents, "{:016x} {}", hash, path);
+    }
+    fs::write(out_dir.join(STATE_FILE), contents).context("could not write the incremental state")
+}
+
+fn content_hash(schema: &Path) -> anyhow::Result<u64> {
+    let contents = fs::read(schema).with_context(|| format!("could not read {:?}", schema))?;
+    Ok(fnv1a(&contents))
+}
+
+/// The module for the code capnpc generated into `file_path`. This has to be exactly the stem capnpc
+/// gave the file, as that's the module it refers to when another schema imports this one.
+pub fn module_name(file_path: &Path) -> anyhow::Result<String> {
+    Ok(file_stem(file_path)?.to_owned())
+}
+
+/// The file stem of `path`, which has to be UTF-8 for module names to be derived from it.
+fn file_stem(path: &Path) -> anyhow::Result<&str> {
+    path.file_stem()
+        .ok_or_else(|| anyhow!("{} has no file name", path.to_string_lossy()))?
+        .to_str()
+        .ok_or_else(|| {
+            anyhow!(
+                "the file name of {} is not valid UTF-8, so no module name can be derived from it",
+                path.to_string_lossy()
+            )
+        })
+}
+
+/// `path` as UTF-8 and with forward slashes, for embedding in generated code. Only Windows separates with
+/// backslashes, elsewhere they are part of the file name.
+pub fn normalize_path(path: &Path) -> anyhow::Result<String> {
+    let path = path.to_str().ok_or_else(|| {
+        anyhow!(
+            "{} is not valid UTF-8, so it can't be referenced from generated code",
+            path.to_string_lossy()
+        )
+    })?;
+    if cfg!(windows) {
+        Ok(path.replace('\\', "/"))
+    } else {
+        Ok(path.to_owned())
+    }
+}
+
+/// A module with `visibility`, such as `pub`, `include!`ing `file_path`, relative to the `output_path`
+/// capnpc was given. The file has to exist, and is referenced through `env!("OUT_DIR")` when it's below
+/// `$OUT_DIR`.
+pub fn append_path(
+    file_path: &Path,
+    output_path: &Path,
+    visibility: &str,
+) -> anyhow::Result<String> {
+    Ok(format!(
+        "{} mod {} {{\n    include!({});\n}}\n",
+        visibility,
+        module_name(file_path)?,
+        include_path(&output_path.join(file_path))?,
+    ))
+}
+
+/// A `#[path]` module declaration with `visibility` for `file_path`, relative to the `output_path` capnpc
+/// was given. The file has to exist, and is referenced by its absolute path.
+pub fn append_path_mod(
+    file_path: &Path,
+    output_path: &Path,
+    visibility: &str,
+) -> anyhow::Result<String> {
+    let generated = output_path.join(file_path);
+    if !generated.is_file() {
+        bail!("capnpc did not generate the expected file {:?}", generated);
+    }
+    Ok(format!(
+        "#[path = {:?}]\n{} mod {};\n",
+        normalize_path(&std::path::absolute(generated)?)?,
+        visibility,
+        module_name(file_path)?,
+    ))
+}
+
+/// The argument to `include!` for a generated file. Files below `OUT_DIR` are referenced through
+/// `env!("OUT_DIR")`, so the helper keeps working wherever the target directory lives, anything else
+/// by its absolute path.
+fn include_path(generated: &Path) -> anyhow::Result<String> {
+    let out_dir = env::var_os("OUT_DIR").map(PathBuf::from);
+    include_path_from(generated, out_dir.as_deref())
+}
+
+/// [`include_path`] with `out_dir` in place of `$OUT_DIR`. Paths are written as escaped string literals,
+/// as a `"` or `\` is legal in file names on Unix.
+fn include_path_from(generated: &Path, out_dir: Option<&Path>) -> anyhow::Result<String> {
+    if !generated.is_file() {
+        bail!("capnpc did not generate the expected file {:?}", generated);
+    }
+    match out_dir.and_then(|out_dir| generated.strip_prefix(out_dir).ok()) {
+        Some(relative) => Ok(format!(
+            "concat!(env!(\"OUT_DIR\"), {:?})",
+            format!("/{}", normalize_path(relative)?)
+        )),
+        None => Ok(format!(
+            "{:?}",
+            normalize_path(&std::path::absolute(generated)?)?
+        )),
+    }
+}
+
+/// A module standing in for the code of `file_path`, for documentation builds without capnp. It only declares
+/// placeholders for the top level types of `schema`, so references to them resolve.
+fn stub_module(file_path: &Path, schema: &Path, visibility: &str) -> anyhow::Result<String> {
+    Ok(format!(
+        "{} mod {} {{\n{}}}\n",
+        visibility,
+        module_name(file_path)?,
+        stub_code(schema)?
+    ))
+}
+
+/// The placeholders for the top level types of `schema` that [`stub_module`] declares.
+fn stub_code(schema: &Path) -> anyhow::Result<String> {
+    let contents = fs::read(schema).with_context(|| format!("could not read {:?}", schema))?;
+    let contents = String::from_utf8_lossy(&contents);
+    let mut module = String::new();
+    for (kind, name, _) in schema_types(&contents) {
+        let _ = match kind {
+            "enum" => writeln!(module, "    pub enum {} {{}}", name),
+            "struct" => writeln!(
+                module,
+                "    pub mod {} {{\n        \
+                        pub struct Owned(());\n        \
+                        pub struct Reader<'a>(::core::marker::PhantomData<&'a ()>);\n        \
+                        pub struct Builder<'a>(::core::marker::PhantomData<&'a ()>);\n    \
+                    }}",
+                type_module_name(name)
+            ),
+            _ => writeln!(
+                module,
+                "    pub mod {} {{\n        pub struct Client(());\n    }}",
+                type_module_name(name)
+            ),
+        };
+    }
+    Ok(module)
+}
+
+/// Fails naming every import of `schemas` that resolves to no file, relative imports being resolved against
+/// the directory of the importing schema and absolute ones against `import_paths`.
+fn check_imports(schemas: &[PathBuf], import_paths: &[PathBuf]) -> anyhow::Result<()> {
+    let mut unresolved = Vec::new();
+    for schema in schemas {
+        let contents = fs::read(schema).with_context(|| format!("could not read {:?}", schema))?;
+        for import in schema_imports(&String::from_utf8_lossy(&contents)) {
+            let resolves = match import.strip_prefix('/') {
+                Some(absolute) => import_paths.iter().any(|dir| dir.join(absolute).is_file()),
+                None => schema
+                    .parent()
+                    .is_some_and(|dir| dir.join(import).is_file()),
+            };
+            if !resolves {
+                unresolved.push(format!("{:?} imports {:?}", schema, import));
+            }
+        }
+    }
+    if !unresolved.is_empty() {
+        bail!(
+            "some imports resolve to no file, relative to the importing schema or below the import paths {:?}:\n{}",
+            import_paths,
+            unresolved.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// The files `schema` imports, as written in its `import` expressions.
+fn schema_imports(schema: &str) -> Vec<&str> {
+    let mut imports = Vec::new();
+    let mut previous = "";
+    let mut chars = schema.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '#' => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '"' => {
+                let mut end = start + 1;
+                while let Some((at, c)) = chars.next() {
+                    end = at;
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+                if previous == "import" {
+                    imports.push(&schema[start + 1..end]);
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((at, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_') {
+                    end = at + c.len_utf8();
+                }
+                previous = &schema[start..end];
+                continue;
+            }
+            _ => {}
+        }
+        if !c.is_whitespace() {
+            previous = "";
+        }
+    }
+    imports
+}
+
+/// The top level structs, enums and interfaces declared in `schema`, as the keyword, the name and whether
+/// the type has generic parameters.
+fn schema_types(schema: &str) -> Vec<(&str, &str, bool)> {
+    let mut types = Vec::new();
+    let mut depth = 0;
+    let mut previous = "";
+    let mut chars = schema.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '#' => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((at, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_') {
+                    end = at + c.len_utf8();
+                }
+                let word = &schema[start..end];
+                if depth == 0 && matches!(previous, "struct" | "enum" | "interface") {
+                    while chars.next_if(|&(_, c)| c.is_whitespace()).is_some() {}
+                    let generic = chars.peek().is_some_and(|&(_, c)| c == '(');
+                    types.push((previous, word, generic));
+                }
+                previous = word;
+                continue;
+            }
+            _ => {}
+        }
+        if !c.is_whitespace() {
+            previous = "";
+        }
+    }
+    types
+}
+
+/// Functions reading and writing messages whose root is the only struct declared at the top level of
+/// `schema`, in both the standard and the packed encoding. Schemas declaring several structs, or a generic
+/// one, don't have an obvious root, so they get none.
+fn codec_helpers(schema: &Path) -> anyhow::Result<Option<String>> {
+    let contents = fs::read(schema).with_context(|| format!("could not read {:?}", schema))?;
+    let contents = String::from_utf8_lossy(&contents);
+    let structs: Vec<_> = schema_types(&contents)
+        .into_iter()
+        .filter(|(kind, _, _)| *kind == "struct")
+        .collect();
+    let [(_, name, false)] = structs[..] else {
+        return Ok(None);
+    };
+    let root = type_module_name(name);
+    let mut helpers = String::new();
+    for (suffix, encoding, read_bound) in [
+        ("", "serialize", "::std::io::Read"),
+        ("_packed", "serialize_packed", "::std::io::BufRead"),
+    ] {
+        let _ = write!(
+            helpers,
+            "
+/// Reads a message whose root is a `{name}`, in the encoding of `::capnp::{encoding}`.
+pub fn read_message{suffix}<R: {read_bound}>(
+    read: R,
+    options: ::capnp::message::ReaderOptions,
+) -> ::capnp::Result<::capnp::message::TypedReader<::capnp::serialize::OwnedSegments, {root}::Owned>> {{
+    Ok(::capnp::message::TypedReader::new(::capnp::{encoding}::read_message(read, options)?))
+}}
+
+/// Writes a message whose root is a `{name}`, in the encoding of `::capnp::{encoding}`.
+pub fn write_message{suffix}<W: ::std::io::Write, A: ::capnp::message::Allocator>(
+    write: W,
+    message: &::capnp::message::TypedBuilder<{root}::Owned, A>,
+) -> ::capnp::Result<()> {{
+    ::capnp::{encoding}::write_message(write, message.borrow_inner())
+}}
+"
+        );
+    }
+    Ok(Some(helpers))
+}
+
+/// The `$Rust.name` annotation, which renames what capnpc generates.
+const NAME_ANNOTATION_ID: u64 = 0xc2fe4c6d100166d0;
+
+/// The name capnpc uses for something called `name` in the schema, which `$Rust.name` overrides.
+fn rust_name<'a>(
+    annotations: capnp::struct_list::Reader<'a, capnpc::schema_capnp::annotation::Owned>,
+    name: &'a str,
+) -> anyhow::Result<&'a str> {
+    for annotation in annotations.iter() {
+        if annotation.get_id() == NAME_ANNOTATION_ID {
+            if let capnpc::schema_capnp::value::Text(text) = annotation.get_value()?.which()? {
+                return Ok(text?);
+            }
+        }
+    }
+    Ok(name)
+}
+
+/// `name` in snake case, as capnpc names accessors and the request methods of clients.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// The keywords capnpc appends a `_` to in the names it generates, copied from capnpc 0.14's `RUST_KEYWORDS`.
+const KEYWORDS: &[&str] = &[
+    "abstract", "alignof", "as", "be", "become", "box", "break", "const", "continue", "crate",
+    "do", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "offsetof", "once", "override", "priv", "proc",
+    "pub", "pure", "ref", "return", "self", "sizeof", "static", "struct", "super", "trait", "true",
+    "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// The module capnpc generates for a struct or interface called `name`.
+fn type_module_name(name: &str) -> String {
+    let mut module = snake_case(name);
+    if KEYWORDS.contains(&module.as_str()) {
+        module.push('_');
+    }
+    module
+}
+
+/// A module with `visibility` containing the contents of `file_path`, relative to `output_dir`.
+pub fn append_contents(
+    file_path: &Path,
+    output_dir: &Path,
+    visibility: &str,
+) -> anyhow::Result<String> {
+    let generated = output_dir.join(file_path);
+    let size = fs::metadata(&generated)
+        .with_context(|| format!("capnpc did not generate the expected file {:?}", generated))?
+        .len();
+    let name = module_name(file_path)?;
+    let mut module = String::with_capacity(size as usize + visibility.len() + name.len() + 16);
+    let _ = writeln!(module, "{} mod {} {{", visibility, name);
+    fs::File::open(&generated)?.read_to_string(&mut module)?;
+    module += "\n}\n";
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_test() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let schema = Path::new("schemas").join(OsStr::from_bytes(b"bad\xff.capnp"));
+        let error = module_name(&schema).unwrap_err().to_string();
+        assert!(error.contains("schemas/bad\u{fffd}.capnp"));
+        assert!(error.contains("not valid UTF-8"));
+        assert!(generated_path(&schema).is_err());
+        assert!(normalize_path(&schema).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn include_path_test() -> anyhow::Result<()> {
+        let out_dir = tempfile::tempdir()?;
+        let generated = out_dir.path().join("sch\"ema\\_capnp.rs");
+        fs::write(&generated, "")?;
+        assert_eq!(
+            include_path_from(&generated, Some(out_dir.path()))?,
+            r#"concat!(env!("OUT_DIR"), "/sch\"ema\\_capnp.rs")"#
+        );
+        assert!(include_path_from(&generated, None)?.ends_with(r#"/sch\"ema\\_capnp.rs""#));
+
+        // as in a home directory like "C:\Users\Zoë\My Documents"
+        let spaced = out_dir.path().join("My Dócuments");
+        fs::create_dir(&spaced)?;
+        let generated = spaced.join("schema_capnp.rs");
+        fs::write(&generated, "")?;
+        assert_eq!(
+            include_path_from(&generated, Some(&spaced))?,
+            r#"concat!(env!("OUT_DIR"), "/schema_capnp.rs")"#
+        );
+        assert!(include_path_from(&generated, None)?.ends_with(r#"/My Dócuments/schema_capnp.rs""#));
+        Ok(())
+    }
+
+    #[test]
+    fn schema_docs_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let schema = dir.path().join("schema.capnp");
+        fs::write(
+            &schema,
+            "@0xdbb9ad1f14bf0b36;  # id\n\n# Some schema.\n#\n#   indented\n\n# A\nstruct A {}\n",
+        )?;
+        assert_eq!(schema_docs(&schema)?, ["Some schema.", "", "  indented"]);
+        fs::write(&schema, "@0xdbb9ad1f14bf0b36;\nstruct A {}\n")?;
+        assert!(schema_docs(&schema)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_capnp_crate_test() {
+        let code = "impl ::capnp::traits::Owned for Owned {}\nuse capnp::private::layout;\n\
+                    use crate::foo_capnp::capnp::Reader;\n[capnp::Word; 2] <::capnp::Word>";
+        assert_eq!(
+            rewrite_capnp_crate(code, "::renamed"),
+            "impl ::renamed::traits::Owned for Owned {}\nuse ::renamed::private::layout;\n\
+             use crate::foo_capnp::capnp::Reader;\n[::renamed::Word; 2] <::renamed::Word>"
+        );
+    }
+
+    #[test]
+    fn default_src_prefix_test() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let schema = |path: &str, contents: &str| -> anyhow::Result<PathBuf> {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, contents)?;
+            Ok(path)
+        };
+        let shallow = schema("proto/net/peer.capnp", "")?;
+        let deep = schema("proto/net/rpc/call.capnp", "")?;
+        let prefix = |files: &[PathBuf]| default_src_prefix(files, &[], root.path());
+        assert_eq!(
+            prefix(&[shallow.clone(), deep.clone()]),
+            Some(root.path().join("proto/net"))
+        );
+        assert_eq!(
+            prefix(std::slice::from_ref(&deep)),
+            Some(root.path().join("proto/net/rpc"))
+        );
+
+        let sibling = schema("proto/storage/disk.capnp", "")?;
+        assert_eq!(
+            prefix(&[deep.clone(), sibling]),
+            Some(root.path().join("proto"))
+        );
+        let top = schema("top.capnp", "")?;
+        assert_eq!(prefix(&[deep.clone(), top]), None);
+
+        // the prefix has to hold what the schemas import relative to themselves
+        schema("proto/shared.capnp", "")?;
+        let importing = schema(
+            "proto/net/rpc/reply.capnp",
+            "using import \"../../shared.capnp\".Shared;\n",
+        )?;
+        assert_eq!(prefix(&[deep, importing]), Some(root.path().join("proto")));
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_match_test() -> anyhow::Result<()> {
+        let glob = Glob::new("schema/**/*.capnp")?;
+        let root = Path::new("/crate");
+        assert!(unreadable_match(
+            &glob,
+            root,
+            Some(Path::new("/crate/schema/net/rpc.capnp"))
+        ));
+        assert!(unreadable_match(&glob, root, Some(root)));
+        assert!(unreadable_match(&glob, root, None));
+        assert!(!unreadable_match(
+            &glob,
+            root,
+            Some(Path::new("/crate/schema/private"))
+        ));
+        assert!(!unreadable_match(
+            &glob,
+            root,
+            Some(Path::new("/crate/target"))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn depfile_test() {
+        let rules = depfile([
+            (
+                PathBuf::from("/out/my app/order_capnp.rs"),
+                vec![
+                    PathBuf::from("/src/order.capnp"),
+                    PathBuf::from("/src/$shared#1.capnp"),
+                ],
+            ),
+            (
+                PathBuf::from("/out/item_capnp.rs"),
+                vec![PathBuf::from("/src/$shared#1.capnp")],
+            ),
+        ]);
+        assert_eq!(
+            rules,
+            "/out/my\\ app/order_capnp.rs: \\\n  /src/order.capnp \\\n  /src/$$shared\\#1.capnp\n\
+             /out/item_capnp.rs: \\\n  /src/$$shared\\#1.capnp\n\
+             \n/src/$$shared\\#1.capnp:\n\
+             \n/src/order.capnp:\n"
+        );
+
+        let rules = depfile([(
+            PathBuf::from(r"C:\out\order_capnp.rs"),
+            vec![PathBuf::from(r"C:\src\order.capnp")],
+        )]);
+        let (target, prerequisite) = if cfg!(windows) {
+            (r"C\:/out/order_capnp.rs", r"C\:/src/order.capnp")
+        } else {
+            (r"C\:\out\order_capnp.rs", r"C\:\src\order.capnp")
+        };
+        assert_eq!(
+            rules,
+            format!("{}: \\\n  {}\n\n{}:\n", target, prerequisite, prerequisite)
+        );
+    }
+
+    #[test]
+    fn batches_test() {
+        let (executable, output) = (PathBuf::from("capnp"), PathBuf::from("out"));
+        let mut cmd = CapnpCompile::new(&executable, &output, false);
+        cmd.import_path("/include");
+        cmd.src_prefix(Path::new("/src"));
+        let files: Vec<PathBuf> = (0..5000)
+            .map(|i| PathBuf::from(format!("/src/schemas/schema{:04}.capnp", i)))
+            .collect();
+        for file in &files {
+            cmd.file(file);
+        }
+        let batches = cmd.batches(&files);
+        assert!(batches.len() > 1);
+        assert_eq!(batches.concat(), files);
+        for batch in batches {
+            let only = cmd.only(batch, &files);
+            assert!(only.args.iter().map(|arg| arg.len() + 1).sum::<usize>() <= MAX_COMMAND_LINE);
+            assert_eq!(only.args[..2], cmd.args[..2]);
+        }
+        assert_eq!(
+            cmd.only(&files[..3], &files).batches(&files[..3]),
+            [&files[..3]]
+        );
+    }
+
+    #[test]
+    fn substitute_test() {
+        let mut config = Config::new();
+        config
+            .substitute("VERSION", "2.1")
+            .substitute("NAME", "orders");
+        let (schema, undefined) = substitute(
+            "# {{NAME}} {{ VERSION }}, {{VERSION}}\n{{not a key}} {{}} {{REGION}}{{OWNER}}{{REGION}}",
+            &config,
+        );
+        assert_eq!(
+            schema,
+            "# orders 2.1, 2.1\n{{not a key}} {{}} {{REGION}}{{OWNER}}{{REGION}}"
+        );
+        assert_eq!(undefined, ["REGION", "OWNER"]);
+        assert_eq!(substitute("{{VERSION", &config).0, "{{VERSION");
+    }
+
+    #[test]
+    fn mirror_path_test() {
+        let mirror = Path::new("/tmp/templated-0");
+        assert_eq!(
+            mirror_path(mirror, Path::new("/src/proto/../common/./base.capnp")),
+            Path::new("/tmp/templated-0/src/common/base.capnp")
+        );
+    }
+
+    #[test]
+    fn schema_imports_test() {
+        let schema = r#"
+            @0xdbb9ad1f14bf0b37;  # using Commented = import "commented.capnp";
+            using Json = import "/capnp/compat/json.capnp";
+            using import "common.capnp".Shared;
+            const greeting :Text = "import \"quoted.capnp\"";
+            struct Message {
+              id @0 :import "ids.capnp".Id;
+            }
+        "#;
+        assert_eq!(
+            schema_imports(schema),
+            ["/capnp/compat/json.capnp", "common.capnp", "ids.capnp"]
+        );
+    }
+
+    #[test]
+    fn check_imports_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let annotations = dir.path().join("annotations");
+        fs::create_dir(&annotations)?;
+        fs::write(annotations.join("units.capnp"), "@0xdbb9ad1f14bf0b38;\n")?;
+        fs::write(dir.path().join("common.capnp"), "@0xdbb9ad1f14bf0b39;\n")?;
+        let schema = [dir.path().join("app.capnp")];
+        fs::write(
+            &schema[0],
+            "@0xdbb9ad1f14bf0b3a;\nusing import \"common.capnp\".Shared;\nusing Units = import \"/units.capnp\";\n",
+        )?;
+        check_imports(&schema, &[annotations])?;
+
+        let error = check_imports(&schema, &[]).unwrap_err().to_string();
+        assert!(error.contains(&format!("{:?} imports \"/units.capnp\"", schema[0])));
+        assert!(!error.contains("common.capnp"));
+        Ok(())
+    }
+
+    #[test]
+    fn schema_types_test() {
+        let schema = r#"
+            @0xdbb9ad1f14bf0b36;  # struct Commented {}
+            using Json = import "/capnp/compat/json.capnp";
+            struct HttpRequest $Json.name("struct Quoted") {
+              struct Nested {}
+              kind @0 :Kind;
+              enum Kind { get @0; }
+            }
+            enum Color{ red @0; }
+            interface Type {}
+            struct Pair (First, Second) {}
+            const struct_count :UInt8 = 1;
+        "#;
+        assert_eq!(
+            schema_types(schema),
+            [
+                ("struct", "HttpRequest", false),
+                ("enum", "Color", false),
+                ("interface", "Type", false),
+                ("struct", "Pair", true)
+            ]
+        );
+        assert_eq!(type_module_name("HttpRequest"), "http_request");
+        assert_eq!(type_module_name("Type"), "type_");
+        assert_eq!(type_module_name("Final"), "final_");
+        assert_eq!(type_module_name("Async"), "async");
+    }
+
+    #[test]
+    fn file_id_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let schema = dir.path().join("schema.capnp");
+        fs::write(
+            &schema,
+            "# comment\n\n@0xdbb9ad1f14bf0b36;  # id\nstruct A {}\n",
+        )?;
+        assert_eq!(file_id(&schema)?, Some(0xdbb9ad1f14bf0b36));
+        fs::write(&schema, "struct A {}\n")?;
+        assert_eq!(file_id(&schema)?, None);
+        // capnp rejects ids without the highest bit set, so they aren't counted
+        fs::write(&schema, "@0x49d2b6a3a58e4f1b;\n")?;
+        assert_eq!(file_id(&schema)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn codec_helpers_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let schema = dir.path().join("schema.capnp");
+        fs::write(
+            &schema,
+            "@0xdbb9ad1f14bf0b36;\nstruct HttpRequest {}\nenum Kind {}\n",
+        )?;
+        let helpers = codec_helpers(&schema)?.unwrap();
+        assert!(helpers.contains("pub fn read_message_packed<R: ::std::io::BufRead>("));
+        assert!(helpers.contains("TypedBuilder<http_request::Owned, A>"));
+
+        fs::write(&schema, "@0xdbb9ad1f14bf0b36;\nstruct A {}\nstruct B {}\n")?;
+        assert_eq!(codec_helpers(&schema)?, None);
+        fs::write(&schema, "@0xdbb9ad1f14bf0b36;\nstruct Box(T) {}\n")?;
+        assert_eq!(codec_helpers(&schema)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn api_surface_test() {
+        let code = "pub mod point {\n  impl <'a,> Reader<'a,>  {\n    #[inline]\n    pub fn get_x(self) -> i32 {\n      self.reader.get_data_field::<i32>(0)\n    }\n  }\n  pub enum Kind {\n    Small = 0,\n  }\n  pub mod _private {\n    pub const TYPE_ID: u64 = 0x1;\n  }\n}\n";
+        assert_eq!(
+            api_surface(code),
+            [
+                "pub mod point",
+                "pub mod point / impl <'a,> Reader<'a,> / pub fn get_x(self) -> i32",
+                "pub mod point / pub enum Kind",
+                "pub mod point / Small = 0,",
+            ]
+        );
+    }
+
+    #[test]
+    fn fnv1a_test() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn find_package_test() -> anyhow::Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manifest = |dir: &str, contents: &str| -> anyhow::Result<PathBuf> {
+            let dir = workspace.path().join(dir);
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join("Cargo.toml"), contents)?;
+            Ok(dir)
+        };
+        manifest(".", "[workspace]\nmembers = [\"crates/*\"]\n")?;
+        let app = manifest("crates/app", "[package]\nname = \"app\"\n")?;
+        let protos = manifest("crates/protos", "[package]\nname = \"protos\"\n")?;
+
+        assert_eq!(find_package(&app, "app")?, app);
+        assert_eq!(find_package(&app, "protos")?, protos);
+        let error = find_package(&app, "missing").unwrap_err().to_string();
+        assert!(error.contains("no package `missing`"));
+        assert!(error.contains("`protos`"));
+        Ok(())
+    }
+
+    #[test]
+    fn check_case_collisions_test() {
+        let schemas = [
+            PathBuf::from("proto/Foo.capnp"),
+            PathBuf::from("./proto/foo.capnp"),
+            PathBuf::from("proto/bar.capnp"),
+        ];
+        let error = check_case_collisions(&schemas, Severity::Deny)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("\"proto/Foo.capnp\" and \"./proto/foo.capnp\""));
+        assert!(check_case_collisions(&schemas, Severity::Allow).is_ok());
+        // the same schema matched twice isn't a collision
+        let schemas = [
+            PathBuf::from("proto/foo.capnp"),
+            PathBuf::from("./proto/foo.capnp"),
+        ];
+        assert!(check_case_collisions(&schemas, Severity::Deny).is_ok());
+    }
+
+    #[test]
+    fn is_warning_test() {
+        assert!(is_warning(
+            "capnp/compiler/module-loader.c++:96: warning: Found exactly the same source file"
+        ));
+        assert!(is_warning("schema.capnp:3:1-5: warning: something"));
+        assert!(!is_warning("schema.capnp:5:1: error: Parse error."));
+        assert!(!is_warning("note: warning: not a location"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_version_test() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let embedded = commandhandle()?;
+        let executable = embedded.path().join("capnp");
+        check_version(&executable, &run_extracted(&executable)?)?;
+
+        let dir = tempfile::tempdir()?;
+        let swapped = dir.path().join("capnp");
+        fs::write(&swapped, "#!/bin/sh\necho \"Cap'n Proto version 0.0.1\"\n")?;
+        fs::set_permissions(&swapped, fs::Permissions::from_mode(0o755))?;
+        let error = check_version(&swapped, &run_extracted(&swapped)?)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Cap'n Proto version 0.0.1"));
+        assert!(error.contains(CAPNP_VERSION));
+
+        // a wrapper that is chatty about what it runs
+        let wrapper = dir.path().join("wrapper");
+        fs::write(
+            &wrapper,
+            format!(
+                "#!/bin/sh\necho \"using the capnp of the toolchain\"\necho \"Cap'n Proto version {}\"\n",
+                CAPNP_VERSION
+            ),
+        )?;
+        fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755))?;
+        check_version(&wrapper, &run_extracted(&wrapper)?)?;
+
+        // as if the disk filled up while extracting
+        let truncated = dir.path().join("truncated");
+        fs::write(&truncated, &fs::read(&executable)?[..64])?;
+        fs::set_permissions(&truncated, fs::Permissions::from_mode(0o755))?;
+        let error = run_extracted(&truncated).unwrap_err().to_string();
+        assert!(error.contains("not the schemas"));
+        Ok(())
+    }
+
+    #[test]
+    fn cached_capnp_test() -> anyhow::Result<()> {
+        let cache_dir = tempfile::tempdir()?;
+        let executable = cached_capnp(&cache_dir.path().join("capnp-import"))?;
+        check_version(&executable, &run_extracted(&executable)?)?;
+        assert_eq!(
+            cached_capnp(&cache_dir.path().join("capnp-import"))?,
+            executable
+        );
+
+        // a binary pruned halfway is extracted again
+        fs::write(&executable, &CAPNP_EXECUTABLE[..64])?;
+        assert_eq!(
+            cached_capnp(&cache_dir.path().join("capnp-import"))?,
+            executable
+        );
+        assert_eq!(fs::read(&executable)?, CAPNP_EXECUTABLE);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fall_back_test() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let broken = || anyhow!("the extracted capnp binary is corrupt");
+        let error = fall_back(broken(), None).unwrap_err();
+        assert!(format!("{:#}", error).contains("no system capnp"));
+        assert!(format!("{:#}", error).contains("corrupt"));
+
+        let dir = tempfile::tempdir()?;
+        let system = dir.path().join("capnp");
+        let script = |version: &str| -> anyhow::Result<()> {
+            fs::write(
+                &system,
+                format!("#!/bin/sh\necho \"Cap'n Proto version {}\"\n", version),
+            )?;
+            fs::set_permissions(&system, fs::Permissions::from_mode(0o755))?;
+            Ok(())
+        };
+        script("0.0.1")?;
+        let error = fall_back(broken(), Some(system.clone())).unwrap_err();
+        assert!(format!("{:#}", error).contains("can't be used instead"));
+
+        script(CAPNP_VERSION)?;
+        assert_eq!(fall_back(broken(), Some(system.clone()))?, system);
+
+        // wrappers can report the version on stderr
+        fs::write(
+            &system,
+            format!(
+                "#!/bin/sh\necho \"Cap'n Proto version {}\" >&2\n",
+                CAPNP_VERSION
+            ),
+        )?;
+        assert_eq!(fall_back(broken(), Some(system.clone()))?, system);
+        Ok(())
+    }
+
+    #[test]
+    fn register_outputs_test() {
+        let outputs = || vec![PathBuf::from("/out/registry/foo_capnp.rs")];
+        let mut config = Config::new();
+        config.pattern("foo.capnp");
+        assert!(register_outputs(outputs(), &config).is_empty());
+        assert!(register_outputs(outputs(), &config).is_empty());
+
+        config.capnp_crate("::capnp_renamed");
+        assert_eq!(register_outputs(outputs(), &config), outputs());
+    }
+
+    #[test]
+    fn schema_crate_metadata_test() -> anyhow::Result<()> {
+        let mut config = Config::new();
+        config
+            .root("/ws/schemas/proto")
+            .root("/ws/schemas/vendor")
+            .pattern("**/*.capnp")
+            .annotations_dir("/ws/annotations")
+            .group(Group::new("/ws/schemas/proto"));
+        let paths =
+            env::join_paths(["/ws/schemas/proto", "/ws/schemas/vendor", "/ws/annotations"])?;
+        assert_eq!(
+            config.schema_crate_metadata(Path::new("/out"))?,
+            vec![
+                "cargo:capnp_import_dir=/out".to_owned(),
+                format!("cargo:capnp_import_paths={}", paths.to_string_lossy()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn declares_file_id_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        for (name, contents, declares) in [
+            ("plain", "@0xc9d2b6a3a58e4f1b;\nstruct A {}\n", true),
+            (
+                "commented",
+                "\n# a schema\n  @0xc9d2b6a3a58e4f1b ; # id\n",
+                true,
+            ),
+            ("high_bit", "@0x49d2b6a3a58e4f1b;\n", false),
+            ("short", "@0xc9d2b6a3;\n", false),
+            ("late", "struct A {}\n@0xc9d2b6a3a58e4f1b;\n", false),
+            ("binary", "\u{0}\u{1}\n", false),
+        ] {
+            fs::write(dir.path().join(name), contents)?;
+            assert_eq!(
+                declares_file_id(&dir.path().join(name)),
+                declares,
+                "{}",
+                name
+            );
+        }
+        assert!(!declares_file_id(&dir.path().join("missing")));
+        Ok(())
+    }
+
+    #[test]
+    fn check_helper_size_test() {
+        let mut config = Config::new();
+        let out_dir = Some(Path::new("out"));
+        assert!(check_helper_size(&config, None, MAX_HELPER_SIZE).is_ok());
+        let advice = |config: &Config, out_dir| {
+            check_helper_size(config, out_dir, MAX_HELPER_SIZE + 1)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(advice(&config, None).contains("Add a build script"));
+        config.single_file(true);
+        assert!(advice(&config, out_dir).contains("Turn off `single_file`"));
+        config.inline_mod(true);
+        assert!(advice(&config, out_dir).contains("Turn off `inline_mod`"));
+    }
+}
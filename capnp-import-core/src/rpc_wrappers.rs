@@ -0,0 +1,138 @@
+//! Methods for the clients of the generated interfaces that build, send and await a request in one call,
+//! written from the code generator request capnp sends alongside the code capnpc generates.
+
+use capnpc::schema_capnp::node;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// An interface declared in a requested file.
+struct Interface<'a> {
+    /// The path of the generated module relative to the module of the file, such as `vault::audit`.
+    path: String,
+    interface: node::interface::Reader<'a>,
+}
+
+/// The wrappers of every requested file in the code generator `request` that declares an interface, by the
+/// name capnp was given the file under. Generic interfaces, interfaces nested in generic structs, and
+/// methods that are generic or take or return a struct declared elsewhere get no wrapper.
+pub(crate) fn rpc_wrappers(request: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut options = capnp::message::ReaderOptions::new();
+    options.traversal_limit_in_words(None);
+    let message = capnp::serialize::read_message(request, options)?;
+    let request: capnpc::schema_capnp::code_generator_request::Reader = message.get_root()?;
+    let mut nodes = BTreeMap::new();
+    for node in request.get_nodes()?.iter() {
+        nodes.insert(node.get_id(), node);
+    }
+
+    let mut wrappers = Vec::new();
+    for file in request.get_requested_files()?.iter() {
+        let mut interfaces = Vec::new();
+        if let Some(node) = nodes.get(&file.get_id()) {
+            collect(*node, &nodes, "", &mut interfaces)?;
+        }
+        let mut code = String::new();
+        for interface in &interfaces {
+            wrap_interface(&mut code, interface, &nodes)?;
+        }
+        if !code.is_empty() {
+            wrappers.push((file.get_filename()?.to_owned(), code));
+        }
+    }
+    Ok(wrappers)
+}
+
+/// Adds the non-generic interfaces nested in `node` to `interfaces`, `path` being that of `node`.
+fn collect<'a>(
+    node: node::Reader<'a>,
+    nodes: &BTreeMap<u64, node::Reader<'a>>,
+    path: &str,
+    interfaces: &mut Vec<Interface<'a>>,
+) -> anyhow::Result<()> {
+    for nested in node.get_nested_nodes()?.iter() {
+        let Some(&nested_node) = nodes.get(&nested.get_id()) else {
+            continue;
+        };
+        if nested_node.get_is_generic() {
+            continue;
+        }
+        let name = crate::rust_name(nested_node.get_annotations()?, nested.get_name()?)?;
+        let path = match path {
+            "" => crate::type_module_name(name),
+            path => format!("{}::{}", path, crate::type_module_name(name)),
+        };
+        match nested_node.which()? {
+            node::Interface(interface) => interfaces.push(Interface {
+                path: path.clone(),
+                interface,
+            }),
+            node::Struct(_) => {}
+            _ => continue,
+        }
+        collect(nested_node, nodes, &path, interfaces)?;
+    }
+    Ok(())
+}
+
+/// Writes an `impl` block for the client of `interface`, with a wrapper for each of its methods that
+/// capnpc declares the params and results of in the module of the interface.
+fn wrap_interface(
+    code: &mut String,
+    interface: &Interface,
+    nodes: &BTreeMap<u64, node::Reader>,
+) -> anyhow::Result<()> {
+    let path = &interface.path;
+    let mut methods = String::new();
+    for method in interface.interface.get_methods()?.iter() {
+        let (Some(params), Some(results)) = (
+            nodes.get(&method.get_param_struct_type()),
+            nodes.get(&method.get_result_struct_type()),
+        ) else {
+            continue;
+        };
+        // structs declared on their own have a module elsewhere, with a brand when generic
+        let implicit = params.get_scope_id() == 0 && results.get_scope_id() == 0;
+        if !implicit || method.get_implicit_parameters()?.len() > 0 {
+            continue;
+        }
+        let name = method.get_name()?;
+        let snake = crate::snake_case(name);
+        let params_module = crate::type_module_name(&format!("{}Params", name));
+        let results_module = crate::type_module_name(&format!("{}Results", name));
+        let empty = match params.which()? {
+            node::Struct(params) => params.get_fields()?.len() == 0,
+            _ => false,
+        };
+        let _ = writeln!(
+            methods,
+            "    /// Sends a `{name}` request{built} and returns the promise of its response.",
+            built = if empty { "" } else { " built by `params`" },
+        );
+        let _ = writeln!(
+            methods,
+            "    pub fn {snake}_async(&self{params}) -> ::capnp::capability::Promise<\
+             ::capnp::capability::Response<{path}::{results_module}::Owned>, ::capnp::Error> {{",
+            params = if empty {
+                String::new()
+            } else {
+                format!(", params: impl FnOnce({path}::{params_module}::Builder<'_>)")
+            },
+        );
+        if empty {
+            let _ = writeln!(methods, "        self.{snake}_request().send().promise");
+        } else {
+            let _ = writeln!(
+                methods,
+                "        let mut request = self.{snake}_request();\n        params(request.get());\n        request.send().promise"
+            );
+        }
+        methods += "    }\n";
+    }
+    if !methods.is_empty() {
+        let _ = write!(
+            code,
+            "\n#[allow(dead_code, clippy::all)]\nimpl {path}::Client {{\n{methods}}}\n"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,424 @@
+//! Mirrors of the generated structs and enums that derive `serde::Serialize` and `serde::Deserialize`,
+//! written from the code generator request capnp sends alongside the code capnpc generates.
+
+use capnpc::schema_capnp::{field, node, type_};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// The field types a mirror can hold.
+enum Ty {
+    Void,
+    Primitive(&'static str),
+    Text,
+    Data,
+    Enum(u64),
+    Struct(u64),
+    List(Box<Ty>),
+}
+
+/// A struct or enum declared in a requested file.
+struct Declaration<'a> {
+    node: node::Reader<'a>,
+    /// The path of the generated type relative to the module of the file, such as `person::phone_number`.
+    path: String,
+    /// The name of the mirror, such as `PersonPhoneNumber`.
+    mirror: String,
+}
+
+/// The `serde_shims` module of every requested file in the code generator `request`, by the name capnp
+/// was given the file under. Structs with unions, groups, generic parameters, interfaces, `AnyPointer`s or
+/// types from other files get no mirror, nor do the structs that contain them.
+pub(crate) fn serde_shims(request: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut options = capnp::message::ReaderOptions::new();
+    options.traversal_limit_in_words(None);
+    let message = capnp::serialize::read_message(request, options)?;
+    let request: capnpc::schema_capnp::code_generator_request::Reader = message.get_root()?;
+    let mut nodes = BTreeMap::new();
+    for node in request.get_nodes()?.iter() {
+        nodes.insert(node.get_id(), node);
+    }
+
+    let mut shims = Vec::new();
+    for file in request.get_requested_files()?.iter() {
+        let mut declarations = BTreeMap::new();
+        if let Some(node) = nodes.get(&file.get_id()) {
+            collect(*node, &nodes, "", "", &mut declarations)?;
+        }
+        let supported = supported(&declarations)?;
+        let mut code = String::from(
+            "\n/// Mirrors of the types in this module that can be serialized with `serde`.\n\
+             #[allow(unused_mut, unused_variables, clippy::all)]\n\
+             pub mod serde_shims {\n",
+        );
+        for (id, declaration) in &declarations {
+            match declaration.node.which()? {
+                node::Enum(enumeration) => {
+                    mirror_enum(&mut code, declaration, enumeration)?;
+                }
+                node::Struct(structure) if supported.contains(id) => {
+                    mirror_struct(&mut code, declaration, structure, &declarations)?;
+                }
+                _ => {}
+            }
+        }
+        code += "}\n";
+        shims.push((file.get_filename()?.to_owned(), code));
+    }
+    Ok(shims)
+}
+
+/// Adds the structs and enums nested in `node` to `declarations`, `path` and `mirror` being those of `node`.
+fn collect<'a>(
+    node: node::Reader<'a>,
+    nodes: &BTreeMap<u64, node::Reader<'a>>,
+    path: &str,
+    mirror: &str,
+    declarations: &mut BTreeMap<u64, Declaration<'a>>,
+) -> anyhow::Result<()> {
+    for nested in node.get_nested_nodes()?.iter() {
+        let Some(&nested_node) = nodes.get(&nested.get_id()) else {
+            continue;
+        };
+        let name = crate::rust_name(nested_node.get_annotations()?, nested.get_name()?)?;
+        let mirror = format!("{}{}", mirror, capitalize(name));
+        let path = match nested_node.which()? {
+            node::Enum(_) => join(path, &capitalize(name)),
+            node::Struct(_) => join(path, &crate::type_module_name(name)),
+            _ => continue,
+        };
+        declarations.insert(
+            nested_node.get_id(),
+            Declaration {
+                node: nested_node,
+                path: path.clone(),
+                mirror: mirror.clone(),
+            },
+        );
+        if !nested_node.get_is_generic() {
+            collect(nested_node, nodes, &path, &mirror, declarations)?;
+        }
+    }
+    Ok(())
+}
+
+/// The structs of `declarations` that can be mirrored, which excludes structs containing themselves
+/// other than through a list, as the mirror would be infinitely large.
+fn supported(declarations: &BTreeMap<u64, Declaration>) -> anyhow::Result<BTreeSet<u64>> {
+    let mut fields = BTreeMap::new();
+    for (id, declaration) in declarations {
+        if let node::Struct(structure) = declaration.node.which()? {
+            if let Some(types) = field_types(declaration.node, structure)? {
+                fields.insert(*id, types);
+            }
+        }
+    }
+    let mut supported: BTreeSet<u64> = fields.keys().copied().collect();
+    loop {
+        let unsupported: Vec<u64> = supported
+            .iter()
+            .copied()
+            .filter(|id| {
+                fields[id]
+                    .iter()
+                    .any(|(_, ty)| !resolves(ty, &supported, declarations))
+                    || contains_itself(*id, *id, &fields, &mut BTreeSet::new())
+            })
+            .collect();
+        if unsupported.is_empty() {
+            return Ok(supported);
+        }
+        for id in unsupported {
+            supported.remove(&id);
+        }
+    }
+}
+
+/// Whether the mirror of `ty` exists.
+fn resolves(ty: &Ty, supported: &BTreeSet<u64>, declarations: &BTreeMap<u64, Declaration>) -> bool {
+    match ty {
+        Ty::Enum(id) => declarations.contains_key(id),
+        Ty::Struct(id) => supported.contains(id),
+        Ty::List(element) => resolves(element, supported, declarations),
+        _ => true,
+    }
+}
+
+/// Whether the struct `id` holds `target` directly, or through structs it holds directly.
+fn contains_itself(
+    target: u64,
+    id: u64,
+    fields: &BTreeMap<u64, Vec<(field::Reader, Ty)>>,
+    visited: &mut BTreeSet<u64>,
+) -> bool {
+    if !visited.insert(id) {
+        return false;
+    }
+    fields.get(&id).is_some_and(|types| {
+        types.iter().any(|(_, ty)| match ty {
+            Ty::Struct(held) => *held == target || contains_itself(target, *held, fields, visited),
+            _ => false,
+        })
+    })
+}
+
+/// The fields of a struct with their types, or `None` if any of them can't be mirrored.
+fn field_types<'a>(
+    node: node::Reader<'a>,
+    structure: node::struct_::Reader<'a>,
+) -> anyhow::Result<Option<Vec<(field::Reader<'a>, Ty)>>> {
+    if node.get_is_generic() || structure.get_discriminant_count() > 0 {
+        return Ok(None);
+    }
+    let mut types = Vec::new();
+    for field in structure.get_fields()?.iter() {
+        let field::Slot(slot) = field.which()? else {
+            return Ok(None);
+        };
+        match ty(slot.get_type()?)? {
+            Some(ty) => types.push((field, ty)),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(types))
+}
+
+/// The mirrored counterpart of `ty`, if it has one.
+fn ty(ty: type_::Reader) -> anyhow::Result<Option<Ty>> {
+    Ok(Some(match ty.which()? {
+        type_::Void(()) => Ty::Void,
+        type_::Bool(()) => Ty::Primitive("bool"),
+        type_::Int8(()) => Ty::Primitive("i8"),
+        type_::Int16(()) => Ty::Primitive("i16"),
+        type_::Int32(()) => Ty::Primitive("i32"),
+        type_::Int64(()) => Ty::Primitive("i64"),
+        type_::Uint8(()) => Ty::Primitive("u8"),
+        type_::Uint16(()) => Ty::Primitive("u16"),
+        type_::Uint32(()) => Ty::Primitive("u32"),
+        type_::Uint64(()) => Ty::Primitive("u64"),
+        type_::Float32(()) => Ty::Primitive("f32"),
+        type_::Float64(()) => Ty::Primitive("f64"),
+        type_::Text(()) => Ty::Text,
+        type_::Data(()) => Ty::Data,
+        type_::Enum(enumeration) => Ty::Enum(enumeration.get_type_id()),
+        type_::Struct(structure) => {
+            if structure.get_brand()?.get_scopes()?.len() > 0 {
+                return Ok(None);
+            }
+            Ty::Struct(structure.get_type_id())
+        }
+        type_::List(list) => match self::ty(list.get_element_type()?)? {
+            Some(element) => Ty::List(Box::new(element)),
+            None => return Ok(None),
+        },
+        type_::Interface(_) | type_::AnyPointer(_) => return Ok(None),
+    }))
+}
+
+fn mirror_enum(
+    code: &mut String,
+    declaration: &Declaration,
+    enumeration: node::enum_::Reader,
+) -> anyhow::Result<()> {
+    let mut variants = Vec::new();
+    for enumerant in enumeration.get_enumerants()?.iter() {
+        variants.push(capitalize(crate::rust_name(
+            enumerant.get_annotations()?,
+            enumerant.get_name()?,
+        )?));
+    }
+    let (mirror, path) = (&declaration.mirror, &declaration.path);
+    let _ = writeln!(
+        code,
+        "    #[derive(Clone, Copy, Debug, PartialEq, Eq, ::serde::Serialize, ::serde::Deserialize)]\n    \
+         pub enum {mirror} {{"
+    );
+    for variant in &variants {
+        let _ = writeln!(code, "        {variant},");
+    }
+    let _ = writeln!(
+        code,
+        "    }}\n\n    impl ::core::convert::From<super::{path}> for {mirror} {{\n        \
+         fn from(value: super::{path}) -> Self {{\n            match value {{"
+    );
+    for variant in &variants {
+        let _ = writeln!(
+            code,
+            "                super::{path}::{variant} => Self::{variant},"
+        );
+    }
+    let _ = writeln!(
+        code,
+        "            }}\n        }}\n    }}\n\n    impl ::core::convert::From<{mirror}> for super::{path} {{\n        \
+         fn from(value: {mirror}) -> Self {{\n            match value {{"
+    );
+    for variant in &variants {
+        let _ = writeln!(
+            code,
+            "                {mirror}::{variant} => Self::{variant},"
+        );
+    }
+    let _ = writeln!(code, "            }}\n        }}\n    }}\n");
+    Ok(())
+}
+
+fn mirror_struct(
+    code: &mut String,
+    declaration: &Declaration,
+    structure: node::struct_::Reader,
+    declarations: &BTreeMap<u64, Declaration>,
+) -> anyhow::Result<()> {
+    let fields = field_types(declaration.node, structure)?.unwrap_or_default();
+    let mut names = Vec::new();
+    for (field, _) in &fields {
+        names.push(crate::snake_case(crate::rust_name(
+            field.get_annotations()?,
+            field.get_name()?,
+        )?));
+    }
+    let (mirror, path) = (&declaration.mirror, &declaration.path);
+
+    let _ = writeln!(
+        code,
+        "    #[derive(Clone, Debug, PartialEq, ::serde::Serialize, ::serde::Deserialize)]\n    \
+         pub struct {mirror} {{"
+    );
+    for (name, (_, ty)) in names.iter().zip(&fields) {
+        let _ = writeln!(
+            code,
+            "        pub {}: {},",
+            field_ident(name),
+            rust_type(ty, declarations)
+        );
+    }
+    let _ = writeln!(
+        code,
+        "    }}\n\n    impl<'a> ::core::convert::TryFrom<super::{path}::Reader<'a>> for {mirror} {{\n        \
+         type Error = ::capnp::Error;\n\n        \
+         fn try_from(reader: super::{path}::Reader<'a>) -> ::capnp::Result<Self> {{\n            \
+         Ok(Self {{"
+    );
+    for (name, (_, ty)) in names.iter().zip(&fields) {
+        let getter = match ty {
+            Ty::Void | Ty::Primitive(_) => format!("reader.get_{name}()"),
+            _ => format!("reader.get_{name}()?"),
+        };
+        let _ = writeln!(
+            code,
+            "                {}: {},",
+            field_ident(name),
+            read(ty, &getter, declarations, 0)
+        );
+    }
+    let _ = writeln!(
+        code,
+        "            }})\n        }}\n    }}\n\n    impl {mirror} {{\n        \
+         /// Writes this into `builder`.\n        \
+         pub fn build(&self, mut builder: super::{path}::Builder<'_>) {{"
+    );
+    for (name, (_, ty)) in names.iter().zip(&fields) {
+        let value = format!("self.{}", field_ident(name));
+        let statement = match ty {
+            Ty::Void => format!("builder.set_{name}(());"),
+            Ty::Primitive(_) => format!("builder.set_{name}({value});"),
+            Ty::Text | Ty::Data => format!("builder.set_{name}(&{value});"),
+            Ty::Enum(_) => format!("builder.set_{name}({value}.into());"),
+            Ty::Struct(_) => format!("{value}.build(builder.reborrow().init_{name}());"),
+            Ty::List(element) => write_list(
+                element,
+                &value,
+                &format!("builder.reborrow().init_{name}({value}.len() as u32)"),
+                0,
+            ),
+        };
+        let _ = writeln!(code, "            {statement}");
+    }
+    let _ = writeln!(code, "        }}\n    }}\n");
+    Ok(())
+}
+
+/// The type of a mirror field holding `ty`.
+fn rust_type(ty: &Ty, declarations: &BTreeMap<u64, Declaration>) -> String {
+    match ty {
+        Ty::Void => "()".to_owned(),
+        Ty::Primitive(primitive) => (*primitive).to_owned(),
+        Ty::Text => "::std::string::String".to_owned(),
+        Ty::Data => "::std::vec::Vec<u8>".to_owned(),
+        Ty::Enum(id) | Ty::Struct(id) => declarations[id].mirror.clone(),
+        Ty::List(element) => format!("::std::vec::Vec<{}>", rust_type(element, declarations)),
+    }
+}
+
+/// An expression turning `value`, as read from capnp, into the mirror of `ty`.
+fn read(ty: &Ty, value: &str, declarations: &BTreeMap<u64, Declaration>, depth: usize) -> String {
+    match ty {
+        Ty::Void | Ty::Primitive(_) => value.to_owned(),
+        Ty::Text => format!("{value}.to_owned()"),
+        Ty::Data => format!("{value}.to_vec()"),
+        Ty::Enum(id) => format!("{}::from({value})", declarations[id].mirror),
+        Ty::Struct(id) => format!(
+            "<{} as ::core::convert::TryFrom<_>>::try_from({value})?",
+            declarations[id].mirror
+        ),
+        Ty::List(element) => {
+            let item = format!("v{depth}");
+            // the iterators of lists of pointers and enums yield results
+            let element_value = match **element {
+                Ty::Void | Ty::Primitive(_) | Ty::Struct(_) => item.clone(),
+                _ => format!("{item}?"),
+            };
+            format!(
+                "{value}.iter().map(|{item}| -> ::capnp::Result<_> {{ Ok({}) }}).collect::<::capnp::Result<::std::vec::Vec<_>>>()?",
+                read(element, &element_value, declarations, depth + 1)
+            )
+        }
+    }
+}
+
+/// A block writing the elements of `value` into the list built by `init`.
+fn write_list(element: &Ty, value: &str, init: &str, depth: usize) -> String {
+    let (list, index, item) = (
+        format!("list{depth}"),
+        format!("i{depth}"),
+        format!("v{depth}"),
+    );
+    let statement = match element {
+        Ty::Void | Ty::Primitive(_) => format!("{list}.set({index} as u32, *{item});"),
+        Ty::Text | Ty::Data => format!("{list}.set({index} as u32, {item});"),
+        Ty::Enum(_) => format!("{list}.set({index} as u32, (*{item}).into());"),
+        Ty::Struct(_) => format!("{item}.build({list}.reborrow().get({index} as u32));"),
+        Ty::List(inner) => write_list(
+            inner,
+            item.as_str(),
+            &format!("{list}.reborrow().init({index} as u32, {item}.len() as u32)"),
+            depth + 1,
+        ),
+    };
+    format!(
+        "{{ let mut {list} = {init}; for ({index}, {item}) in {value}.iter().enumerate() {{ {statement} }} }}"
+    )
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+/// The mirror field for the accessor `name`, which has to be a raw identifier if it's a keyword.
+fn field_ident(name: &str) -> String {
+    match name {
+        // these can't be raw identifiers
+        "crate" | "self" | "super" => format!("{}_", name),
+        name if crate::type_module_name(name) != name => format!("r#{}", name),
+        name => name.to_owned(),
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    match path {
+        "" => name.to_owned(),
+        path => format!("{}::{}", path, name),
+    }
+}
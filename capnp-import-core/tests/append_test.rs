@@ -0,0 +1,37 @@
+use capnp_import_core::Config;
+use std::fs;
+
+#[test]
+fn append_test() -> anyhow::Result<()> {
+    let out_dir = tempfile::tempdir()?;
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("first.capnp"),
+        "@0xd5b3c7b3f2a1e901;\nstruct First {}\n",
+    )?;
+    fs::write(
+        schemas.path().join("second.capnp"),
+        "@0xd5b3c7b3f2a1e902;\nstruct Second {}\n",
+    )?;
+    std::env::set_var("OUT_DIR", out_dir.path());
+
+    let mut second = Config::new();
+    second
+        .pattern("second.capnp")
+        .root(schemas.path())
+        .append(true);
+    let mut first = Config::new();
+    first
+        .pattern("first.capnp")
+        .root(schemas.path())
+        .append(true);
+    second.build()?;
+    first.build()?;
+    let helper = first.build()?;
+
+    let contents = fs::read_to_string(helper)?;
+    assert_eq!(contents.matches("pub mod first_capnp {").count(), 1);
+    assert_eq!(contents.matches("pub mod second_capnp {").count(), 1);
+    assert!(contents.find("first_capnp") < contents.find("second_capnp"));
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use capnp_import_core::Config;
+use std::fs;
+
+#[test]
+fn absolute_root_test() -> anyhow::Result<()> {
+    let out_dir = tempfile::tempdir()?;
+    let schemas = out_dir.path().join("generated-schemas");
+    fs::create_dir(&schemas)?;
+    fs::write(
+        schemas.join("generated.capnp"),
+        "@0xe0b7ff464fbc7ee3;\nstruct Generated {\n  id @0 :UInt64;\n}\n",
+    )?;
+    std::env::set_var("OUT_DIR", out_dir.path());
+
+    let helper = Config::new().pattern("*.capnp").root(&schemas).build()?;
+    let contents = fs::read_to_string(helper)?;
+    assert!(contents.contains("pub mod generated_capnp {"));
+    assert!(contents.contains("\"/generated_capnp.rs\""));
+    assert!(out_dir.path().join("generated_capnp.rs").is_file());
+
+    // cargo sets no CARGO_FEATURE_CAPNP_EXTENDED, so nothing is compiled
+    fs::remove_file(out_dir.path().join("generated_capnp.rs"))?;
+    let helper = Config::new()
+        .pattern("*.capnp")
+        .root(&schemas)
+        .feature("capnp-extended")
+        .build()?;
+    assert!(!fs::read_to_string(helper)?.contains("pub mod"));
+    assert!(!out_dir.path().join("generated_capnp.rs").exists());
+    Ok(())
+}
@@ -0,0 +1,44 @@
+include!(concat!(env!("OUT_DIR"), "/extract_bin.rs"));
+
+#[test]
+fn binary_decision_test() {
+    assert_eq!(commandhandle().unwrap().path().exists(), true);
+}
+
+#[test]
+fn capnp_version_test() {
+    let handle = commandhandle().unwrap();
+    let output = std::process::Command::new(handle.path().join("capnp"))
+        .arg("--version")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        format!("Cap'n Proto version {}", capnp_import_core::capnp_version())
+    );
+}
+
+#[test]
+fn capnp_binary_hash_test() {
+    use sha2::{Digest, Sha256};
+    let hash: String = Sha256::digest(CAPNP_EXECUTABLE)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    assert_eq!(capnp_import_core::capnp_binary_hash(), hash);
+    assert_eq!(CAPNP_BINARY_HASH, hash);
+}
+
+#[test]
+fn diagnose_test() {
+    let report = capnp_import_core::diagnose();
+    assert!(report.contains(&format!("({})", capnp_import_core::capnp_version())));
+    assert!(report.contains("system capnp: "));
+    assert!(report.contains("built from source: "));
+    assert!(report.contains("standard include dir: "));
+    assert!(report.contains(&format!("acquired: {}", capnp_import_core::capnp_source())));
+    assert!(report.contains(&format!(
+        "embedded capnp sha256: {}",
+        capnp_import_core::capnp_binary_hash()
+    )));
+}
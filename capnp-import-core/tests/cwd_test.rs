@@ -0,0 +1,35 @@
+use capnp_import_core::{process_inner, Config};
+use std::fs;
+
+// Changes the working directory of the whole test process, so it's the only test in this binary.
+#[test]
+fn cwd_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::create_dir(schemas.path().join("proto"))?;
+    fs::write(
+        schemas.path().join("proto/order.capnp"),
+        "@0xc9d2b6a3a58e4f18;\nstruct Order {\n  id @0 :UInt64;\n}\n",
+    )?;
+    // linked schemas are matched by the file they point to
+    #[cfg(unix)]
+    {
+        fs::write(
+            schemas.path().join("shared.capnp"),
+            "@0xc9d2b6a3a58e4f19;\nstruct Shared {\n  id @0 :UInt64;\n}\n",
+        )?;
+        std::os::unix::fs::symlink("../shared.capnp", schemas.path().join("proto/shared.capnp"))?;
+        std::os::unix::fs::symlink("missing.capnp", schemas.path().join("proto/dangling.capnp"))?;
+    }
+    let elsewhere = tempfile::tempdir()?;
+    std::env::set_current_dir(elsewhere.path())?;
+
+    let mut config = Config::new();
+    config.pattern("proto/*.capnp").root(schemas.path());
+    let mut expected = vec![schemas.path().join("proto/order.capnp")];
+    if cfg!(unix) {
+        expected.push(schemas.path().join("proto/shared.capnp"));
+    }
+    assert_eq!(config.matched_files()?, expected);
+    assert!(process_inner(&config, None)?.contains("pub mod order_capnp {"));
+    Ok(())
+}
@@ -0,0 +1,478 @@
+use capnp_import_core::{
+    append_path, capnp_binary_hash, capnp_version, compile_reader, generate_into, matched_files,
+    normalize_path, process_inner, Config, Plugin,
+};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn library_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("library.capnp"),
+        "@0xc9d2b6a3a58e4f01;\nstruct Library {\n  name @0 :Text;\n}\n",
+    )?;
+    let mut config = Config::new();
+    config.pattern("*.capnp").root(schemas.path());
+    let helper = process_inner(&config, None)?;
+    assert!(helper.starts_with(
+        "// This file is autogenerated by capnp-import\n#[rustfmt::skip]\npub mod library_capnp {"
+    ));
+
+    config.header("DO NOT EDIT\n\nregenerate with `cargo build`");
+    let helper = process_inner(&config, None)?;
+    assert!(helper.starts_with(
+        "// DO NOT EDIT\n//\n// regenerate with `cargo build`\n#[rustfmt::skip]\npub mod"
+    ));
+
+    let helper = process_inner(config.no_header(), None)?;
+    assert!(helper.starts_with("#[rustfmt::skip]\npub mod library_capnp {"));
+
+    let helper = process_inner(config.rustfmt_skip(false), None)?;
+    assert!(helper.starts_with("pub mod library_capnp {"));
+
+    // this test has no OUT_DIR, so the generated file is referenced by its absolute path
+    let out_dir = tempfile::tempdir()?;
+    fs::write(out_dir.path().join("library_capnp.rs"), "")?;
+    let module = append_path(Path::new("library_capnp.rs"), out_dir.path(), "pub")?;
+    let absolute = normalize_path(&out_dir.path().join("library_capnp.rs"))?;
+    assert_eq!(
+        module,
+        format!(
+            "pub mod library_capnp {{\n    include!({:?});\n}}\n",
+            absolute
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn generate_into_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    let schema = schemas.path().join("evolving.capnp");
+    fs::write(
+        &schema,
+        "@0xc9d2b6a3a58e4f03;\nstruct Evolving {\n  a @0 :Text;\n}\n",
+    )?;
+    let mut config = Config::new();
+    config.pattern("*.capnp").root(schemas.path());
+    let before = tempfile::tempdir()?;
+    let helper = config.generate_into(before.path().join("before"))?;
+    assert_eq!(helper, before.path().join("before/capnp_include.rs"));
+
+    fs::write(
+        &schema,
+        "@0xc9d2b6a3a58e4f03;\nstruct Evolving {\n  a @0 :Text;\n  b @1 :Text;\n}\n",
+    )?;
+    let after = tempfile::tempdir()?;
+    config.generate_into(after.path())?;
+    let old = fs::read_to_string(before.path().join("before/evolving_capnp.rs"))?;
+    let new = fs::read_to_string(after.path().join("evolving_capnp.rs"))?;
+    assert!(!old.contains("get_b"));
+    assert!(new.contains("get_b"));
+    assert!(fs::read_to_string(after.path().join("capnp_include.rs"))?.contains("include!"));
+
+    let helper = generate_into(&["annotations/rust.capnp"], after.path())?;
+    assert!(fs::read_to_string(helper)?.contains("pub mod rust_capnp"));
+    Ok(())
+}
+
+#[test]
+fn compile_reader_test() -> anyhow::Result<()> {
+    let schema = "@0xc9d2b6a3a58e4f02;\nstruct Scratch {\n  id @0 :UInt64;\n}\n";
+    let code = compile_reader("scratch-pad.capnp", schema.as_bytes())?;
+    assert!(code.starts_with("#[rustfmt::skip]\npub mod scratch_pad_capnp {"));
+    assert!(code.contains("pub mod scratch {"));
+    assert!(compile_reader("../scratch.capnp", schema.as_bytes()).is_err());
+    Ok(())
+}
+
+#[test]
+fn matched_files_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::create_dir(schemas.path().join("nested"))?;
+    for file in ["b.capnp", "a.capnp", "nested/c.capnp", "README.md"] {
+        fs::write(schemas.path().join(file), "")?;
+    }
+    let files = Config::new()
+        .pattern("**/*.capnp")
+        .pattern("a.capnp")
+        .root(schemas.path())
+        .matched_files()?;
+    let expected: Vec<_> = ["a.capnp", "b.capnp", "nested/c.capnp"]
+        .iter()
+        .map(|file| schemas.path().join(file))
+        .collect();
+    assert_eq!(files, expected);
+
+    assert!(matched_files(&["no-such-dir/*.capnp"]).is_err());
+    Ok(())
+}
+
+#[test]
+fn emitter_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("emitted.capnp"),
+        "@0xc9d2b6a3a58e4f04;\nstruct Emitted {}\n",
+    )?;
+    let out_dir = tempfile::tempdir()?;
+    let helper = process_inner(
+        Config::new()
+            .pattern("*.capnp")
+            .root(schemas.path())
+            .no_header()
+            .emitter(|file_path: &Path, output_path: &Path| {
+                assert!(output_path.join(file_path).is_file());
+                Ok(format!(
+                    "pub(crate) mod {} {{}}\n",
+                    capnp_import_core::module_name(file_path)?
+                ))
+            }),
+        Some(out_dir.path()),
+    )?;
+    assert_eq!(
+        helper,
+        "#[rustfmt::skip]\npub(crate) mod emitted_capnp {}\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn import_resolver_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("order.capnp"),
+        "@0xc9d2b6a3a58e4f06;\nusing import \"/fetched/money.capnp\".Money;\nstruct Order {\n  total @0 :Money;\n}\n",
+    )?;
+    let mut config = Config::new();
+    config.pattern("*.capnp").root(schemas.path());
+    assert!(process_inner(&config, None).is_err());
+
+    config.import_resolver(|schemas: &[std::path::PathBuf], scratch: &Path| {
+        assert_eq!(schemas.len(), 1);
+        fs::create_dir(scratch.join("fetched"))?;
+        fs::write(
+            scratch.join("fetched/money.capnp"),
+            "@0xc9d2b6a3a58e4f07;\nstruct Money {\n  cents @0 :Int64;\n}\n",
+        )?;
+        Ok(vec![scratch.to_owned()])
+    });
+    let helper = process_inner(&config, None)?;
+    assert!(helper.contains("pub mod order_capnp {"));
+    Ok(())
+}
+
+#[test]
+fn api_fingerprint_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    let schema = schemas.path().join("stable.capnp");
+    let mut config = Config::new();
+    config.pattern("*.capnp").root(schemas.path());
+    fs::write(
+        &schema,
+        "@0xc9d2b6a3a58e4f09;\nstruct Stable {\n  a @0 :Text;\n  b @1 :UInt32;\n}\n",
+    )?;
+    let fingerprint = config.api_fingerprint()?;
+    assert!(fingerprint.starts_with("stable_capnp "));
+    assert_eq!(fingerprint.lines().count(), 1);
+
+    // comments and the order of declarations aren't part of the API
+    fs::write(
+        &schema,
+        "@0xc9d2b6a3a58e4f09;\n# documented\nstruct Stable {\n  b @1 :UInt32;\n  a @0 :Text;\n}\n",
+    )?;
+    assert_eq!(config.api_fingerprint()?, fingerprint);
+
+    fs::write(
+        &schema,
+        "@0xc9d2b6a3a58e4f09;\nstruct Stable {\n  a @0 :Text;\n  renamed @1 :UInt32;\n}\n",
+    )?;
+    assert_ne!(config.api_fingerprint()?, fingerprint);
+    Ok(())
+}
+
+#[test]
+fn emit_modules_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("handoff.capnp"),
+        "@0xc9d2b6a3a58e4f0a;\nstruct Handoff {\n  id @0 :UInt64;\n}\n",
+    )?;
+    let mut config = Config::new();
+    config.pattern("*.capnp").root(schemas.path());
+    let out_dir = tempfile::tempdir()?;
+    let files = config.generate_files_into(out_dir.path())?;
+    assert_eq!(files, [out_dir.path().join("handoff_capnp.rs")]);
+    assert!(out_dir.path().join("capnp_include.rs").is_file());
+
+    // codegen only, even when the modules would otherwise be spliced into the helper
+    let out_dir = tempfile::tempdir()?;
+    let files = config
+        .emit_modules(false)
+        .inline_mod(true)
+        .generate_files_into(out_dir.path())?;
+    assert_eq!(files, [out_dir.path().join("handoff_capnp.rs")]);
+    assert!(fs::read_to_string(&files[0])?.contains("pub mod handoff {"));
+    assert!(!out_dir.path().join("capnp_include.rs").exists());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn plugin_test() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("plugged.capnp"),
+        "@0xc9d2b6a3a58e4f0b;\nstruct Plugged {\n  id @0 :UInt64;\n}\n",
+    )?;
+    // stands in for a fork of capnpc-rust, writing its argument instead of the generated code
+    let plugin = schemas.path().join("capnpc-fork");
+    fs::write(
+        &plugin,
+        "#!/bin/sh\ncat > request.bin\ntest -s request.bin && echo \"pub const FORKED: &str = \\\"$1\\\";\" > plugged_capnp.rs\n",
+    )?;
+    fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755))?;
+
+    let mut config = Config::new();
+    config
+        .pattern("*.capnp")
+        .root(schemas.path())
+        .plugin(Plugin::new(&plugin).arg("flavour").clone());
+    let helper = process_inner(&config, None)?;
+    assert!(helper.contains("pub mod plugged_capnp {"));
+    assert!(helper.contains("pub const FORKED: &str = \"flavour\";"));
+
+    config.plugin(Plugin::new(schemas.path().join("missing")));
+    let error = process_inner(&config, None).unwrap_err().to_string();
+    assert!(error.contains("does not exist"));
+    Ok(())
+}
+
+#[test]
+fn manifest_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("order.capnp"),
+        "@0xc9d2b6a3a58e4f0c;\nusing Item = import \"item.capnp\".Item;\nstruct Order {\n  items @0 :List(Item);\n}\n",
+    )?;
+    fs::write(
+        schemas.path().join("item.capnp"),
+        "@0xc9d2b6a3a58e4f0d;\nstruct Item {\n  sku @0 :Text;\n}\n",
+    )?;
+    let out_dir = tempfile::tempdir()?;
+    let mut config = Config::new();
+    config
+        .pattern("order.capnp")
+        .root(schemas.path())
+        .manifest("capnp-manifest.json");
+    process_inner(&config, Some(out_dir.path()))?;
+
+    let manifest = fs::read_to_string(out_dir.path().join("capnp-manifest.json"))?;
+    let schema = schemas.path().join("order.capnp");
+    assert!(manifest.contains(&format!(
+        "{{\"schema\": {:?}, \"generated\": \"order_capnp.rs\"}}",
+        schema
+    )));
+    assert!(manifest.contains(&format!("\"compiler_version\": {:?}", capnp_version())));
+    assert!(manifest.contains(&format!("\"compiler_sha256\": {:?}", capnp_binary_hash())));
+    assert!(manifest.contains("\"imports\": [\"item.capnp\"]"));
+    assert!(manifest.contains("annotations\""));
+    Ok(())
+}
+
+#[test]
+fn source_tree_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::create_dir(schemas.path().join("billing"))?;
+    fs::write(
+        schemas.path().join("account.capnp"),
+        "@0xc9d2b6a3a58e4f0e;\nstruct Account {\n  id @0 :UInt64;\n}\n",
+    )?;
+    fs::write(
+        schemas.path().join("billing/invoice.capnp"),
+        "@0xc9d2b6a3a58e4f0f;\nstruct Invoice {\n  total @0 :UInt64;\n  corrections @1 :List(Invoice);\n}\n",
+    )?;
+    let mut config = Config::new();
+    config
+        .pattern("**/*.capnp")
+        .root(schemas.path())
+        .nested(true)
+        .parent("proto");
+    let src = tempfile::tempdir()?;
+    let tree = src.path().join("proto");
+    let mut written = config.generate_source_tree(&tree)?;
+    written.sort();
+    assert_eq!(
+        written,
+        [
+            "account_capnp.rs",
+            "billing/invoice_capnp.rs",
+            "billing/mod.rs",
+            "mod.rs"
+        ]
+        .map(|file| tree.join(file))
+    );
+    let root = fs::read_to_string(tree.join("mod.rs"))?;
+    assert!(root.ends_with("pub mod account_capnp;\npub mod billing;\n"));
+    assert!(fs::read_to_string(tree.join("billing/mod.rs"))?.ends_with("pub mod invoice_capnp;\n"));
+    assert!(fs::read_to_string(tree.join("billing/invoice_capnp.rs"))?
+        .contains("crate::proto::billing::invoice_capnp::invoice::Owned"));
+    config.check_source_tree(&tree)?;
+
+    fs::write(tree.join("account_capnp.rs"), "// edited by hand\n")?;
+    fs::write(tree.join("billing/removed_capnp.rs"), "")?;
+    let error = config.check_source_tree(&tree).unwrap_err().to_string();
+    assert!(error.contains("account_capnp.rs\" is out of date"));
+    assert!(error.contains("removed_capnp.rs\" is not generated by any schema"));
+    Ok(())
+}
+
+#[test]
+fn src_prefix_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::create_dir_all(schemas.path().join("proto/app/admin"))?;
+    fs::write(
+        schemas.path().join("proto/app/user.capnp"),
+        "@0xc9d2b6a3a58e4f10;\nstruct User {\n  name @0 :Text;\n}\n",
+    )?;
+    fs::write(
+        schemas.path().join("proto/app/admin/role.capnp"),
+        "@0xc9d2b6a3a58e4f11;\nstruct Role {\n  name @0 :Text;\n}\n",
+    )?;
+    let mut config = Config::new();
+    config
+        .pattern("**/*.capnp")
+        .root(schemas.path())
+        .emit_modules(false);
+    let out_dir = tempfile::tempdir()?;
+    let mut files = config.generate_files_into(out_dir.path())?;
+    files.sort();
+    assert_eq!(
+        files,
+        ["admin/role_capnp.rs", "user_capnp.rs"].map(|file| out_dir.path().join(file))
+    );
+
+    let out_dir = tempfile::tempdir()?;
+    let mut files = config
+        .src_prefix("proto")
+        .generate_files_into(out_dir.path())?;
+    files.sort();
+    assert_eq!(
+        files,
+        ["app/admin/role_capnp.rs", "app/user_capnp.rs"].map(|file| out_dir.path().join(file))
+    );
+    Ok(())
+}
+
+#[test]
+fn depfile_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    let annotations = schemas.path().join("annotations");
+    fs::create_dir(&annotations)?;
+    fs::write(
+        annotations.join("units.capnp"),
+        "@0xc9d2b6a3a58e4f12;\nannotation unit(field) :Text;\n",
+    )?;
+    fs::write(
+        schemas.path().join("common.capnp"),
+        "@0xc9d2b6a3a58e4f13;\nusing Units = import \"/units.capnp\";\nstruct Amount {\n  value @0 :Float64 $Units.unit(\"EUR\");\n}\n",
+    )?;
+    fs::write(
+        schemas.path().join("payment.capnp"),
+        "@0xc9d2b6a3a58e4f14;\nusing import \"common.capnp\".Amount;\nstruct Payment {\n  amount @0 :Amount;\n}\n",
+    )?;
+    let out_dir = tempfile::tempdir()?;
+    let mut config = Config::new();
+    config
+        .pattern("payment.capnp")
+        .root(schemas.path())
+        .annotations_dir(&annotations)
+        .depfile("capnp.d");
+    process_inner(&config, Some(out_dir.path()))?;
+
+    let rules = fs::read_to_string(out_dir.path().join("capnp.d"))?;
+    let rule = rules.split("\n\n").next().unwrap();
+    let mut prerequisites = rule.split(" \\\n  ");
+    assert_eq!(
+        prerequisites.next(),
+        Some(format!("{}:", out_dir.path().join("payment_capnp.rs").display()).as_str())
+    );
+    let mut prerequisites: Vec<_> = prerequisites.map(str::trim_end).collect();
+    prerequisites.sort();
+    let mut expected = [
+        schemas.path().join("payment.capnp"),
+        schemas.path().join("common.capnp"),
+        annotations.join("units.capnp"),
+    ]
+    .map(|path| path.display().to_string());
+    expected.sort();
+    assert_eq!(prerequisites, expected);
+    assert!(rules.contains(&format!(
+        "\n{}:\n",
+        annotations.join("units.capnp").display()
+    )));
+    Ok(())
+}
+
+#[test]
+fn substitute_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::create_dir(schemas.path().join("common"))?;
+    fs::write(
+        schemas.path().join("common/base.capnp"),
+        "@0xc9d2b6a3a58e4f13;\nstruct Base {\n  id @0 :UInt64;\n}\n",
+    )?;
+    let release = "@0xc9d2b6a3a58e4f14;\nusing import \"common/base.capnp\".Base;\n\
+                   const version :Text = \"{{VERSION}}\";\nconst build :Text = \"{{ BUILD_ID }}\";\n";
+    fs::write(schemas.path().join("release.capnp"), release)?;
+    let mut config = Config::new();
+    config
+        .pattern("release.capnp")
+        .root(schemas.path())
+        .substitute("VERSION", "1.4.2");
+    let error = process_inner(&config, None).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "no value to substitute for `{{{{BUILD_ID}}}}` in {:?}",
+            schemas.path().join("release.capnp")
+        )
+    );
+
+    let helper = process_inner(config.substitute("BUILD_ID", "nightly"), None)?;
+    assert!(helper.contains("pub mod release_capnp {"));
+    assert!(helper.contains("\"1.4.2\""));
+    assert!(helper.contains("\"nightly\""));
+    assert_eq!(
+        fs::read_to_string(schemas.path().join("release.capnp"))?,
+        release
+    );
+    Ok(())
+}
+
+#[test]
+fn rpc_wrappers_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("store.capnp"),
+        "@0xc9d2b6a3a58e4f15;\nstruct Entry {\n  key @0 :Text;\n}\n\
+         interface Store {\n  get @0 (key :Text) -> (value :Text);\n  put @1 Entry -> ();\n  \
+         keys @2 [T] (prefix :T) -> ();\n}\n\
+         interface Cache(Value) {\n  fetch @0 (key :Text) -> (value :Value);\n}\n",
+    )?;
+    let mut config = Config::new();
+    config.pattern("*.capnp").root(schemas.path());
+    assert!(!process_inner(&config, None)?.contains("_async("));
+
+    let helper = process_inner(config.rpc_wrappers(true), None)?;
+    assert!(helper.contains("impl store::Client {"));
+    assert!(helper
+        .contains("pub fn get_async(&self, params: impl FnOnce(store::get_params::Builder<'_>))"));
+    for unwrapped in ["put_async", "keys_async", "fetch_async"] {
+        assert!(!helper.contains(unwrapped), "{}", unwrapped);
+    }
+    Ok(())
+}
@@ -0,0 +1,7 @@
+use capnp_import_core::{capnp_source, process_inner, Config};
+
+fn main() {
+    let contents = process_inner(Config::new().pattern("schema/*.capnp"), None).unwrap();
+    assert!(contents.contains("pub mod point_capnp {"));
+    println!("{}", capnp_source());
+}
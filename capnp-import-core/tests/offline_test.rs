@@ -0,0 +1,43 @@
+use std::path::Path;
+use std::process::Command;
+
+// Builds a crate depending on capnp-import-core with deny-net-fetch and no capnp on PATH, so capnp has to
+// be built from the vendored sources, then compiles a schema with the result. That takes a C++ toolchain
+// and cmake, and a while, so it only runs when asked for with `cargo test -- --ignored`.
+#[test]
+#[ignore = "builds capnp from source"]
+fn offline_test() {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/offline-test");
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("offline-test");
+    let executable = format!("capnp{}", std::env::consts::EXE_SUFFIX);
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let path = std::env::join_paths(
+        std::env::split_paths(&path).filter(|dir| !dir.join(&executable).is_file()),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO"))
+        .arg("run")
+        .arg("--manifest-path")
+        .arg(fixture.join("Cargo.toml"))
+        .current_dir(&fixture)
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .env("PATH", path)
+        // the fixture builds on its own, without whatever this test was built with
+        .env_remove("CAPNP_IMPORT_DENY_BUILD")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // a rerun reuses the binary built by the first run in the same target dir
+    assert!(
+        stdout.starts_with("built from the vendored sources")
+            || stdout.starts_with("reused an earlier build"),
+        "{}",
+        stdout
+    );
+}
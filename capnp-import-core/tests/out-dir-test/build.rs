@@ -0,0 +1,6 @@
+fn main() {
+    capnp_import_core::Config::new()
+        .pattern("schema/*.capnp")
+        .build()
+        .unwrap();
+}
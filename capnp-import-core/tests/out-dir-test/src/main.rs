@@ -0,0 +1,8 @@
+include!(concat!(env!("OUT_DIR"), "/capnp_include.rs"));
+
+fn main() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut label = message.init_root::<label_capnp::label::Builder>();
+    label.set_text("ünïcode");
+    println!("{}", label.into_reader().get_text().unwrap());
+}
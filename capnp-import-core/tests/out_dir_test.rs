@@ -0,0 +1,29 @@
+use std::path::Path;
+use std::process::Command;
+
+// Builds a crate whose build script compiles a schema with capnp-import-core, in a target dir with a
+// space and non-ASCII characters in it, as under a Windows home directory like "C:\Users\Zoë\My Documents".
+// Every path baked into the generated code and into capnp-import-core itself then contains them. That
+// builds capnp-import-core and possibly capnp from scratch, so it only runs when asked for with
+// `cargo test -- --ignored`.
+#[test]
+#[ignore = "builds capnp-import-core from scratch"]
+fn out_dir_test() {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/out-dir-test");
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("out dir ü");
+
+    let output = Command::new(env!("CARGO"))
+        .arg("run")
+        .arg("--manifest-path")
+        .arg(fixture.join("Cargo.toml"))
+        .current_dir(&fixture)
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "ünïcode");
+}
@@ -0,0 +1,29 @@
+// Kept apart from the other tests, as it sets environment variables the whole process reads
+use capnp_import_core::Config;
+use std::fs;
+
+#[test]
+fn preview_test() -> anyhow::Result<()> {
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("preview.capnp"),
+        "@0xc9d2b6a3a58e4f08;\nstruct Preview {}\n",
+    )?;
+    let out_dir = tempfile::tempdir()?;
+    std::env::set_var("OUT_DIR", out_dir.path());
+    let mut config = Config::new();
+    config.pattern("*.capnp").root(schemas.path());
+    let helper = config.preview()?;
+    assert!(helper.contains("pub mod preview_capnp {"));
+
+    // the generated file is left alone rather than compiled again
+    let generated = out_dir.path().join("preview_capnp.rs");
+    fs::write(&generated, "// from an earlier build")?;
+    std::env::set_var("CAPNP_IMPORT_PREVIEW", "1");
+    assert_eq!(config.preview()?, helper);
+    assert_eq!(fs::read_to_string(&generated)?, "// from an earlier build");
+
+    fs::remove_file(&generated)?;
+    assert!(config.preview().is_err());
+    Ok(())
+}
@@ -0,0 +1,36 @@
+// Kept apart from the other tests, as it sets an environment variable the whole process reads
+use capnp_import_core::{process_inner, Config};
+use std::fs;
+
+#[test]
+fn import_schema_crate_test() -> anyhow::Result<()> {
+    let owned = tempfile::tempdir()?;
+    fs::write(
+        owned.path().join("base.capnp"),
+        "@0xc9d2b6a3a58e4f16;\nstruct Base {\n  id @0 :UInt64;\n}\n",
+    )?;
+    let schemas = tempfile::tempdir()?;
+    fs::write(
+        schemas.path().join("order.capnp"),
+        "@0xc9d2b6a3a58e4f17;\nusing import \"/base.capnp\".Base;\nstruct Order {\n  base @0 :Base;\n}\n",
+    )?;
+    let mut config = Config::new();
+    config
+        .pattern("order.capnp")
+        .root(schemas.path())
+        .import_schema_crate("shared-schemas");
+    let error = process_inner(&config, None).unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("DEP_SHARED_SCHEMAS_CAPNP_IMPORT_PATHS is not set."));
+
+    std::env::set_var(
+        "DEP_SHARED_SCHEMAS_CAPNP_IMPORT_PATHS",
+        std::env::join_paths([owned.path()])?,
+    );
+    let helper = process_inner(&config, None)?;
+    assert!(helper.contains("pub mod order_capnp {"));
+    assert!(helper.contains("crate::base_capnp::base::"));
+    assert!(!helper.contains("pub mod base_capnp {"));
+    Ok(())
+}
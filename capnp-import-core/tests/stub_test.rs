@@ -0,0 +1,72 @@
+// Runs a script in place of capnp, which writes a file of empty modules for every schema it's given,
+// so finding schemas, building the capnp command line, naming modules and emitting them is tested
+// without a compiler.
+#![cfg(unix)]
+
+use capnp_import_core::{process_inner, Config, Order};
+use std::fs;
+use std::path::Path;
+
+fn stub() -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/stub-capnp/capnp")
+}
+
+fn schemas() -> anyhow::Result<tempfile::TempDir> {
+    let schemas = tempfile::tempdir()?;
+    fs::create_dir(schemas.path().join("net"))?;
+    fs::write(
+        schemas.path().join("scratch-pad.capnp"),
+        "@0xc9d2b6a3a58e4f11;\nstruct Scratch {}\n",
+    )?;
+    fs::write(
+        schemas.path().join("net/address.capnp"),
+        "@0xc9d2b6a3a58e4f12;\nstruct Address {}\nenum Family { inet @0; }\n",
+    )?;
+    fs::write(schemas.path().join("notes.txt"), "not a schema")?;
+    Ok(schemas)
+}
+
+#[test]
+fn discovery_test() -> anyhow::Result<()> {
+    let schemas = schemas()?;
+    let mut config = Config::new();
+    config
+        .pattern("**/*.capnp")
+        .root(schemas.path())
+        .compiler(stub());
+    let helper = process_inner(&config, None)?;
+    let address = helper.find("pub mod address_capnp {").unwrap();
+    let scratch = helper.find("pub mod scratch_pad_capnp {").unwrap();
+    // sorted by path, so net/ comes first
+    assert!(address < scratch);
+    assert!(!helper.contains("notes"));
+
+    let helper = process_inner(
+        config
+            .order(Order::Input)
+            .pattern("*.capnp")
+            .pattern("net/*.capnp"),
+        None,
+    )?;
+    assert!(helper.find("pub mod address_capnp {").unwrap() < helper.find("scratch_pad").unwrap());
+    Ok(())
+}
+
+#[test]
+fn emission_test() -> anyhow::Result<()> {
+    let schemas = schemas()?;
+    let out_dir = tempfile::tempdir()?;
+    let mut config = Config::new();
+    config
+        .pattern("**/*.capnp")
+        .root(schemas.path())
+        .nested(true)
+        .compiler(stub());
+    let helper = process_inner(&config, Some(out_dir.path()))?;
+    assert!(helper.contains("pub mod net {"));
+    assert!(helper.contains("include!("));
+
+    let generated = fs::read_to_string(out_dir.path().join("net/address_capnp.rs"))?;
+    assert_eq!(generated, "pub mod address {}\npub mod family {}\n");
+    Ok(())
+}
@@ -0,0 +1,16 @@
+// Kept apart from the other tests, as it sets an environment variable the whole process reads
+include!(concat!(env!("OUT_DIR"), "/extract_bin.rs"));
+
+#[test]
+fn tmpdir_test() -> anyhow::Result<()> {
+    let extract_dir = tempfile::tempdir()?;
+    std::env::set_var("CAPNP_IMPORT_TMPDIR", extract_dir.path());
+    let handle = commandhandle()?;
+    assert!(handle.path().starts_with(extract_dir.path()));
+    assert!(handle.path().join("capnp").is_file());
+
+    std::env::set_var("CAPNP_IMPORT_TMPDIR", extract_dir.path().join("missing"));
+    let error = commandhandle().unwrap_err().to_string();
+    assert!(error.contains("CAPNP_IMPORT_TMPDIR"));
+    Ok(())
+}
@@ -0,0 +1,22 @@
+// Compiled by cross_file_test next to the helper it generates, which has to be in the crate root since the
+// generated code refers to the imported schema as `crate::money_v2_capnp`
+include!("capnp_include.rs");
+
+fn main() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut invoice = message.init_root::<invoice_capnp::invoice::Builder>();
+    invoice.set_number(7);
+    let mut total: money_v2_capnp::money::Builder = invoice.reborrow().init_total();
+    total.set_cents(1250);
+    total.set_currency(money_v2_capnp::money::Currency::Eur);
+    let mut line = invoice.reborrow().init_lines(1).get(0);
+    line.set_description("capnp");
+    line.init_amount().set_cents(1250);
+
+    let invoice = invoice.into_reader();
+    let total = invoice.get_total().unwrap();
+    assert_eq!(total.get_cents(), 1250);
+    assert_eq!(total.get_currency().unwrap(), money_v2_capnp::money::Currency::Eur);
+    let line = invoice.get_lines().unwrap().get(0);
+    assert_eq!(line.get_amount().unwrap().get_cents(), 1250);
+}
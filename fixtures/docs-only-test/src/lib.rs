@@ -0,0 +1,11 @@
+//! Documents a crate whose schemas are never compiled.
+
+capnp_import::capnp_import!("schema/*.capnp");
+
+/// Re-exported to check that the module exists.
+pub use point_capnp as points;
+
+/// Only resolves through the placeholder types.
+pub fn same_point(point: points::point::Reader) -> points::point::Reader {
+    point
+}
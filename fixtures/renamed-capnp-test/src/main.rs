@@ -0,0 +1,9 @@
+// there is no `capnp` dependency, so the generated code only compiles through the renamed one
+capnp_import::capnp_import!("schema/*.capnp", capnp_crate = "::capnp_renamed");
+
+fn main() {
+    let mut message = capnp_renamed::message::Builder::new_default();
+    let mut point = message.init_root::<point_capnp::point::Builder>();
+    point.set_x(1);
+    assert_eq!(point.into_reader().get_x(), 1);
+}
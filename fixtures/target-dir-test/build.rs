@@ -0,0 +1,2 @@
+// only here so that cargo sets OUT_DIR, which makes capnp_import! include! its generated code
+fn main() {}
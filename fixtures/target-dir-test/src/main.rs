@@ -0,0 +1,17 @@
+capnp_import::capnp_import!("schema/*.capnp");
+
+// the same schemas, declared as `#[path]` modules pointing into OUT_DIR
+mod path_mod {
+    capnp_import::capnp_import!("schema/*.capnp", path_mod = true);
+}
+
+fn main() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut point = message.init_root::<point_capnp::point::Builder>();
+    point.set_x(1);
+    assert_eq!(point.into_reader().get_x(), 1);
+
+    let mut point = message.init_root::<path_mod::point_capnp::point::Builder>();
+    point.set_y(2);
+    assert_eq!(point.into_reader().get_y(), 2);
+}
@@ -1,101 +1,598 @@
 //! Download and/or build official Cap-n-Proto compiler (capnp) release for the current OS and architecture
 
-use anyhow::anyhow;
-use convert_case::{Case, Casing};
+use capnp_import_core::{package_dir, process_inner, Config, Group, Order, Plugin, Severity};
 use proc_macro::TokenStream;
-use proc_macro2::TokenStream as TokenStream2;
-use quote::{format_ident, quote};
+use proc_macro2::Span;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::{env, fs, path::Path};
-use syn::parse::Parser;
-use syn::{LitStr, Token};
-use walkdir::WalkDir;
-use wax::{BuildError, Walk};
-
-use anyhow::Context;
-
-include!(concat!(env!("OUT_DIR"), "/extract_bin.rs"));
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream, Parser};
+use syn::punctuated::Punctuated;
+use syn::{token, Ident, LitBool, LitStr, Token};
 
 /// `capnp_import!(pattern_1, pattern_2, ..., pattern_n)` compiles all the .capnp files at the locations of those files
 /// and replaces itself with the resulting contents wrapped in appropriate module structure.
-/// Resulting rust files from that compilation are then deleted.
+///
+/// Schemas that import from different roots can be split into groups, which are each walked from their own
+/// root and compiled with it as an import path, plus any extra `import_path`s:
+/// `capnp_import!(group(root = "proto", ["**/*.capnp"]), group(root = "third_party/proto", ["**/*.capnp"]))`.
+///
+/// Schemas can import the annotations of capnpc as `/rust.capnp`, and directories of other annotation schemas are
+/// added with `annotations_dir = "dir"`, which every group resolves its absolute imports against.
+/// `absolute_import_root = "proto"` does the same under the name that suits a directory of ordinary schemas,
+/// so that `import "/myorg/base.capnp"` resolves to `proto/myorg/base.capnp`.
+///
+/// When the invoking crate has a build script, the generated code is kept in its `OUT_DIR` and each module
+/// `include!`s it from there. Otherwise, or when `inline_mod = true` is passed alongside the patterns, the
+/// generated code is spliced directly into the modules and the resulting rust files are deleted.
+///
+/// Instead of patterns, `index = "schema_index.txt"` compiles exactly the schemas listed in that file, one path
+/// per line. Blank lines and `#` comments are skipped, and every listed schema has to exist.
+///
+/// `path_mod = true` declares each module as `#[path = "..."] pub mod foo_capnp;` pointing at the generated
+/// file, instead of `include!`ing it, which some IDEs resolve better.
+///
+/// `single_file = true` concatenates the modules into one file in `OUT_DIR`, which is `include!`d once instead
+/// of once per schema.
+///
+/// With `incremental = true` and a build script, only schemas whose contents changed since the last build
+/// are recompiled.
+///
+/// The generated code reaches the `capnp` runtime through `::capnp`. For a renamed dependency or a re-export,
+/// pass its path instead, as in `capnp_crate = "::capnp_renamed"`.
+///
+/// The generated code is written for version 0.14 of the `capnp` crate. `capnp_runtime = "0.14"` states the
+/// version your crate depends on, so a mismatch is reported up front rather than as errors in the generated code.
+///
+/// `formatter = "rustfmt --edition 2021"` runs that command on every generated file, which is warned about if it
+/// fails, unless `formatter_failures = "deny"` or `"allow"` is passed too.
+///
+/// Every module is marked `#[rustfmt::skip]`, so `cargo fmt` leaves the generated code alone. `rustfmt_skip = false`
+/// drops the attribute, such as for code already run through a `formatter`.
+///
+/// `codec_helpers = true` adds `read_message` and `write_message` functions, plus `_packed` variants, to the
+/// modules of schemas that declare a single top level struct.
+///
+/// `expose = ["Person", "Date"]` makes the modules `pub(crate)` and re-exports only those top level types next
+/// to them, which narrows the public API. Everything is still generated and compiled.
+///
+/// `reexport = ["person_capnp::person as user"]` adds `pub(crate) use person_capnp::person as user;` next to the
+/// modules, to curate the names the rest of the crate uses. The names have to be unique identifiers.
+///
+/// `module = "dev", cfg = feature = "dev-schemas"` declares the modules inside `#[cfg(feature = "dev-schemas")]
+/// pub mod dev`, for schema sets that are only part of some build profiles. `cfg` takes any cfg predicate, such
+/// as `all(unix, not(test))`, and without `module` gates every module on its own. Unlike `feature`, the gate
+/// never keeps the schemas from being compiled.
+///
+/// `feature = "extended"` puts every module behind `#[cfg(feature = "extended")]`, for schemas that are only
+/// part of the crate with that cargo feature. Cargo doesn't tell proc macros which features are enabled, so the
+/// schemas are still compiled and have to exist either way.
+///
+/// `serde_shims = true`, with the `serde` feature, adds a `serde_shims` module to every module, mirroring its
+/// structs and enums with types that derive `Serialize` and `Deserialize`.
+///
+/// `rpc_wrappers = true` adds a `get_async` method for every method `get` to the clients of the interfaces,
+/// building the params with a closure and returning the promise of the response to `.await`.
+///
+/// `exclude = ["**/legacy/*.capnp"]` leaves the schemas matching those patterns out. `allow = ["clippy::all"]`
+/// adds `#[allow(...)]` with those lints to every module, and `visibility = "pub(crate)"` declares the modules
+/// with that visibility instead of `pub`.
+///
+/// Defaults for every invocation in a crate can be put in a `.capnp-import.toml` next to its `Cargo.toml`,
+/// which can set `import_paths`, `modules` (`"include"`, `"inline"`, `"path"` or `"single_file"`), `exclude`,
+/// `allow` and `visibility`. Options passed to the macro take precedence, and lists add to those of the file.
+///
+/// `plugin = "tools/capnpc-rust-fork"` generates the code with that plugin instead of the bundled capnpc. It's
+/// run like capnp runs plugins, with the code generator request on its stdin, and has to write a `foo_capnp.rs`
+/// for each `foo.capnp`.
+///
+/// `package = "protos"` matches the patterns below the directory of that package of the workspace instead of the
+/// crate root, rather than going through a path like `../../protos`. It fails naming the packages of the
+/// workspace if there's no such package.
+///
+/// `roots = ["proto", "vendor/proto"]` matches the patterns below each of those directories instead, with each root
+/// as an import path of its schemas. A schema below several of them is compiled from the first.
+///
+/// `parent = "proto"` declares all the modules inside a single `pub mod proto`, to keep the crate root tidy. Two
+/// schemas that would be modules of the same name in it are an error.
+///
+/// `extensions = ["capnp", "capnproto"]` only compiles matched files with one of those extensions, as a safety net
+/// against broad patterns like `"**/*"`. Patterns are matched first, then the files they match are filtered.
+///
+/// `sniff_schemas = true` also compiles matched files without one of those extensions whose first line declares a
+/// file id, like `@0xdbb9ad1f14bf0b36;`, and leaves out the other ones, for schemas not named `.capnp`.
+///
+/// `generated_src = true` adds `pub const GENERATED_SRC: &str` holding the generated code as text, for tools that
+/// inspect it at runtime.
+///
+/// `lift_docs = true` turns the comment block at the top of each schema into the doc comment of its module.
+///
+/// Schemas that share a file id are warned about, `duplicate_ids = "deny"` turns that into an error and
+/// `duplicate_ids = "allow"` silences it. Schemas whose paths only differ in case, which collide on macOS and
+/// Windows, are warned about too, unless `case_collisions = "deny"` or `"allow"` is passed. Empty schemas are
+/// skipped with a warning, or rejected with `empty_files = "deny"`. `strict = true` checks that every matched
+/// schema was emitted as a module of its own, and fails naming the schemas that weren't.
+///
+/// `best_effort = true` skips schemas that fail to compile with a warning, leaving their modules out, instead of
+/// failing the build. That can silently omit types, so it's only meant for development, and off by default.
+///
+/// The generated files are written below the deepest directory all the schemas are in, so
+/// `"proto/app/**/*.capnp"` generates `OUT_DIR/user_capnp.rs` rather than `OUT_DIR/proto/app/user_capnp.rs`.
+/// `src_prefix = "proto"` writes them below that directory of the root instead.
+///
+/// `depfile = "capnp.d"` writes a Make-style dependency file into `OUT_DIR`, with a rule for every generated file
+/// depending on its schema and everything that schema imports, directly or not.
+///
+/// `substitute = ["VERSION=1.4.2", "REGION=eu"]` replaces `{{VERSION}}` and `{{REGION}}` in the matched schemas
+/// before compiling a copy of them, leaving the originals untouched. With `substitute_env = true` placeholders
+/// without a value are read from the environment variable of the same name. A placeholder with no value at all
+/// fails naming the key and the schema.
+///
+/// `check_imports = true` resolves the imports of every schema before compiling, and fails naming each import that
+/// resolves to no file and the schema importing it.
+///
+/// `manifest = "capnp-manifest.json"` writes a JSON manifest of how the code was generated into `OUT_DIR`: the
+/// compiler and its version, the import paths passed to it, the schemas compiled and the files they import.
+///
+/// Warnings of capnp are passed on, and `deny_warnings = true` turns them into an error listing them.
+///
+/// `verbose = true` runs capnp with `--verbose`, shows what it reports as warnings, and adds the command line
+/// it was run with to the error when compilation fails.
+///
+/// Modules are emitted sorted by the path of their schema. With `order = "input"` they follow the order of
+/// the patterns instead, with the schemas matched by each pattern sorted by path. `order = "discovery"` skips
+/// sorting altogether, leaving the schemas matched by each pattern in the order the filesystem lists them.
+///
+/// Patterns are matched from the crate root. With `relative_to_caller = true` they are matched from the
+/// directory of the file invoking the macro instead, which falls back to the crate root when the compiler
+/// doesn't know that file. The roots of groups stay relative to the crate root.
 #[proc_macro]
 pub fn capnp_import(input: TokenStream) -> TokenStream {
-    let parser = syn::punctuated::Punctuated::<LitStr, Token![,]>::parse_separated_nonempty;
-    let path_patterns = parser.parse(input).unwrap();
-    let path_patterns = path_patterns.into_iter().map(|item| item.value());
-    let result = process_inner(path_patterns).unwrap();
-    result.into()
-}
-
-fn process_inner<I>(path_patterns: I) -> anyhow::Result<TokenStream2>
-where
-    I: IntoIterator,
-    I::Item: AsRef<str>,
-{
-    let mut cmd = capnpc::CompilerCommand::new();
-
-    let output_dir = commandhandle().context("could not create temporary capnp binary")?;
-    let cmdpath = output_dir.path().join("capnp");
-    cmd.capnp_executable(cmdpath);
-    cmd.output_path(&output_dir);
-
-    let globs = path_patterns
-        .into_iter()
-        .flat_map(|s| {
-            wax::walk(s.as_ref(), ".")
-                .map_err(BuildError::into_owned)
-                .map(Walk::into_owned)
+    let mut args = syn::parse_macro_input!(input as Args);
+    if args.relative_to_caller {
+        if let Some(dir) = caller_dir() {
+            args.config.root(dir);
+        }
+    }
+    expand(&args.config)
+}
+
+/// `capnp_import_dir!("schemas")` compiles every .capnp file below `schemas` into modules nested like the
+/// directories they are in, so `schemas/net/rpc.capnp` becomes `net::rpc_capnp`, with the schemas directly in
+/// `schemas` at the top level. It takes the same options as `capnp_import!` after the directory, as in
+/// `capnp_import_dir!("schemas", lift_docs = true)`, and `package = "protos"` finds the directory in that package
+/// of the workspace. `strip_prefix = "com/example"` leaves the modules for those
+/// directories out, so `schemas/com/example/rpc.capnp` becomes `rpc_capnp`. With `relative_to_caller = true`
+/// the directory is found next to the file invoking the macro rather than in the crate root.
+///
+/// The generated code refers to these modules from the crate root, so invoke it at the top level of your crate.
+/// Imports across directories only resolve if the imported schema sets its module with `$Rust.parentModule`.
+#[proc_macro]
+pub fn capnp_import_dir(input: TokenStream) -> TokenStream {
+    let mut args = syn::parse_macro_input!(input as DirArgs);
+    let dir = match caller_dir().filter(|_| args.relative_to_caller) {
+        Some(caller) => caller.join(&args.dir),
+        None => match &args.package {
+            Some(package) => package.join(&args.dir),
+            None => PathBuf::from(&args.dir),
+        },
+    };
+    args.config.root(dir);
+    expand(&args.config)
+}
+
+/// The directory of the file invoking the macro, if the compiler knows it.
+fn caller_dir() -> Option<PathBuf> {
+    let file = proc_macro::Span::call_site().local_file()?;
+    Some(file.parent()?.to_owned())
+}
+
+/// Compiles the schemas of `config`, or turns the error into a `compile_error!`.
+fn expand(config: &Config) -> TokenStream {
+    let out_dir = std::env::var_os("OUT_DIR").map(PathBuf::from);
+    match process_inner(config, out_dir.as_deref()) {
+        Ok(result) => TokenStream::from_str(&result).unwrap_or_else(|e| {
+            syn::Error::new(
+                Span::call_site(),
+                format!("the generated code is not valid Rust: {}", e),
+            )
+            .to_compile_error()
+            .into()
+        }),
+        Err(e) => syn::Error::new(Span::call_site(), format!("{:#}", e))
+            .to_compile_error()
+            .into(),
+    }
+}
+
+mod kw {
+    syn::custom_keyword!(group);
+}
+
+/// Glob patterns or lists of them, optionally mixed with `group(...)`s and `option = value` pairs.
+struct Args {
+    config: Config,
+    relative_to_caller: bool,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut config = defaults()?;
+        let mut relative_to_caller = false;
+        let mut has_patterns = false;
+        while !input.is_empty() {
+            if input.peek(LitStr) || input.peek(token::Bracket) {
+                for pattern in parse_patterns(input)? {
+                    config.pattern(pattern);
+                }
+                has_patterns = true;
+            } else if input.peek(kw::group) && input.peek2(token::Paren) {
+                input.parse::<kw::group>()?;
+                let content;
+                syn::parenthesized!(content in input);
+                config.group(parse_group(&content)?);
+                has_patterns = true;
+            } else {
+                let name: Ident = input.parse()?;
+                input.parse::<Token![=]>()?;
+                has_patterns |= name == "index";
+                if name == "relative_to_caller" {
+                    relative_to_caller = input.parse::<LitBool>()?.value;
+                } else {
+                    parse_option(&name, input, &mut config)?;
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        if !has_patterns {
+            return Err(input.error("expected at least one pattern"));
+        }
+        Ok(Self {
+            config,
+            relative_to_caller,
         })
-        .flatten();
-
-    for entry_result in globs {
-        let entry: PathBuf = entry_result?.into_path();
-        if entry.is_file() {
-            cmd.file(entry);
-        }
-    }
-    cmd.run()?;
-    let mut helperfile = TokenStream2::new();
-    for entry_result in WalkDir::new(output_dir.path()) {
-        let file_path = entry_result?.into_path();
-        if file_path.is_file()
-            && file_path
-                .file_name()
-                .ok_or(anyhow!("Couldn't parse file: {:?}", file_path))?
-                .to_str()
-                .ok_or(anyhow!("Couldn't convert to &str: {:?}", file_path))?
-                .ends_with("_capnp.rs")
-        {
-            helperfile.extend(append_path(&file_path)?);
-        }
-    }
-    Ok(helperfile)
-    // When TempDir goes out of scope, it gets deleted
-}
-
-fn append_path(file_path: &Path) -> anyhow::Result<TokenStream2> {
-    let file_stem = file_path
-        .file_stem()
-        .ok_or(anyhow!("Couldn't parse file: {:?}", file_path))?
-        .to_str()
-        .ok_or(anyhow!("Couldn't convert to &str: {:?}", file_path))?
-        .to_case(Case::Snake);
-
-    let contents = TokenStream2::from_str(&fs::read_to_string(file_path)?).map_err(|_| {
-        anyhow!(
-            "Couldn't convert file contents to TokenStream: {:?}",
-            file_path
-        )
-    })?;
-    let module_name = format_ident!("{}", file_stem);
-    let helperfile = quote! {
-        pub mod #module_name {
-            #contents
+    }
+}
+
+/// The value of the option called `name`, applied to `config`.
+fn parse_option(name: &Ident, input: ParseStream, config: &mut Config) -> syn::Result<()> {
+    match name.to_string().as_str() {
+        "index" => config.index(input.parse::<LitStr>()?.value()),
+        "annotations_dir" | "absolute_import_root" => {
+            config.annotations_dir(input.parse::<LitStr>()?.value())
+        }
+        "inline_mod" => config.inline_mod(input.parse::<LitBool>()?.value),
+        "order" => config.order(parse_order(input)?),
+        "incremental" => config.incremental(input.parse::<LitBool>()?.value),
+        "duplicate_ids" => config.duplicate_ids(parse_severity(input)?),
+        "case_collisions" => config.case_collisions(parse_severity(input)?),
+        "empty_files" => config.empty_files(parse_severity(input)?),
+        "strict" => config.strict(input.parse::<LitBool>()?.value),
+        "strip_prefix" => config.strip_prefix(input.parse::<LitStr>()?.value()),
+        "src_prefix" => config.src_prefix(input.parse::<LitStr>()?.value()),
+        "deny_warnings" => config.deny_warnings(input.parse::<LitBool>()?.value),
+        "codec_helpers" => config.codec_helpers(input.parse::<LitBool>()?.value),
+        "lift_docs" => config.lift_docs(input.parse::<LitBool>()?.value),
+        "capnp_crate" => {
+            config.capnp_crate(parse_code(input, syn::Path::parse_mod_style, "a path")?)
+        }
+        "formatter" => config.formatter(input.parse::<LitStr>()?.value()),
+        "formatter_failures" => config.formatter_failures(parse_severity(input)?),
+        "verbose" => config.verbose(input.parse::<LitBool>()?.value),
+        "single_file" => config.single_file(input.parse::<LitBool>()?.value),
+        "path_mod" => config.path_mod(input.parse::<LitBool>()?.value),
+        "capnp_runtime" => config.runtime(input.parse::<LitStr>()?.value()),
+        "serde_shims" => config.serde_shims(input.parse::<LitBool>()?.value),
+        "rpc_wrappers" => config.rpc_wrappers(input.parse::<LitBool>()?.value),
+        "feature" => config.feature(input.parse::<LitStr>()?.value()),
+        "visibility" => {
+            config.visibility(parse_code(input, syn::Visibility::parse, "a visibility")?)
+        }
+        "plugin" => config.plugin(Plugin::new(input.parse::<LitStr>()?.value())),
+        "package" => config.root(parse_package(input)?),
+        "roots" => {
+            for root in parse_patterns(input)? {
+                config.root(root);
+            }
+            config
+        }
+        "parent" | "module" => config.parent(input.parse::<LitStr>()?.value()),
+        "cfg" => config.cfg(parse_cfg(input)?),
+        "generated_src" => config.generated_src(input.parse::<LitBool>()?.value),
+        "best_effort" => config.best_effort(input.parse::<LitBool>()?.value),
+        "manifest" => config.manifest(input.parse::<LitStr>()?.value()),
+        "check_imports" => config.check_imports(input.parse::<LitBool>()?.value),
+        "depfile" => config.depfile(input.parse::<LitStr>()?.value()),
+        "exclude" => {
+            for pattern in parse_patterns(input)? {
+                config.exclude(pattern);
+            }
+            config
+        }
+        "extensions" => {
+            for extension in parse_patterns(input)? {
+                config.extension(extension);
+            }
+            config
+        }
+        "allow" => {
+            for lint in parse_codes(input, syn::Path::parse_mod_style, "a lint")? {
+                config.allow(lint);
+            }
+            config
+        }
+        "expose" => {
+            for name in parse_codes(input, Ident::parse_any, "a type name")? {
+                config.expose(name);
+            }
+            config
+        }
+        "reexport" => {
+            let span = input.span();
+            for reexport in parse_patterns(input)? {
+                let Some((path, name)) = reexport.split_once(" as ") else {
+                    return Err(syn::Error::new(
+                        span,
+                        format!("expected `\"path as Name\"`, not {:?}", reexport),
+                    ));
+                };
+                config.reexport(path.trim(), name.trim());
+            }
+            config
+        }
+        "substitute" => {
+            let span = input.span();
+            for substitution in parse_patterns(input)? {
+                let Some((key, value)) = substitution.split_once('=') else {
+                    return Err(syn::Error::new(
+                        span,
+                        format!("expected `\"KEY=value\"`, not {:?}", substitution),
+                    ));
+                };
+                config.substitute(key, value);
+            }
+            config
+        }
+        "substitute_env" => config.substitute_env(input.parse::<LitBool>()?.value),
+        "rustfmt_skip" => config.rustfmt_skip(input.parse::<LitBool>()?.value),
+        "sniff_schemas" => config.sniff_schemas(input.parse::<LitBool>()?.value),
+        _ => {
+            return Err(syn::Error::new(
+                name.span(),
+                format!("unknown option `{}`", name),
+            ))
         }
     };
-    Ok(helperfile)
+    Ok(())
+}
+
+/// A cfg predicate, such as `feature = "dev-schemas"` or `all(unix, not(test))`, up to the next comma.
+fn parse_cfg(input: ParseStream) -> syn::Result<String> {
+    let mut tokens = proc_macro2::TokenStream::new();
+    while !input.is_empty() && !input.peek(Token![,]) {
+        tokens.extend([input.parse::<proc_macro2::TokenTree>()?]);
+    }
+    let predicate: syn::Meta = syn::parse2(tokens.clone())
+        .map_err(|e| syn::Error::new(e.span(), "expected a cfg predicate"))?;
+    check_cfg(&predicate)?;
+    Ok(tokens.to_string())
+}
+
+/// Fails unless `predicate` is a name, a name with a string value, or `all`, `any` or `not` of predicates.
+fn check_cfg(predicate: &syn::Meta) -> syn::Result<()> {
+    let invalid = |message| Err(syn::Error::new_spanned(predicate.path(), message));
+    if predicate.path().get_ident().is_none() {
+        return invalid("cfg options are plain names, not paths");
+    }
+    match predicate {
+        syn::Meta::Path(_) => Ok(()),
+        syn::Meta::NameValue(option) => match &option.value {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(_),
+                ..
+            }) => Ok(()),
+            _ => invalid("the value of a cfg option has to be a string literal"),
+        },
+        syn::Meta::List(list) => {
+            let name = list.path.get_ident().map(Ident::to_string);
+            let predicates =
+                list.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated)?;
+            match name.as_deref() {
+                Some("not") if predicates.len() != 1 => {
+                    invalid("`not` takes exactly one predicate")
+                }
+                Some("all" | "any" | "not") => predicates.iter().try_for_each(check_cfg),
+                _ => invalid("only `all`, `any` and `not` combine cfg predicates"),
+            }
+        }
+    }
+}
+
+/// The directory of the workspace package named by a string literal.
+fn parse_package(input: ParseStream) -> syn::Result<PathBuf> {
+    let package: LitStr = input.parse()?;
+    package_dir(&package.value()).map_err(|e| syn::Error::new(package.span(), format!("{:#}", e)))
+}
+
+/// The configuration read from `.capnp-import.toml` in the crate root, if there is one.
+fn defaults() -> syn::Result<Config> {
+    let crate_root = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default());
+    let mut config = Config::new();
+    config
+        .defaults_file(crate_root.join(".capnp-import.toml"))
+        .map_err(|e| syn::Error::new(Span::call_site(), format!("{:#}", e)))?;
+    Ok(config)
+}
+
+/// A directory, optionally followed by `option = value` pairs.
+struct DirArgs {
+    dir: String,
+    config: Config,
+    relative_to_caller: bool,
+    package: Option<PathBuf>,
+}
+
+impl Parse for DirArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let dir: LitStr = input.parse()?;
+        let mut config = defaults()?;
+        config.pattern("**/*.capnp").nested(true);
+        let mut relative_to_caller = false;
+        let mut package = None;
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let name: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            if name == "relative_to_caller" {
+                relative_to_caller = input.parse::<LitBool>()?.value;
+            } else if name == "package" {
+                package = Some(parse_package(input)?);
+            } else {
+                parse_option(&name, input, &mut config)?;
+            }
+        }
+        Ok(Self {
+            dir: dir.value(),
+            config,
+            relative_to_caller,
+            package,
+        })
+    }
+}
+
+/// A single pattern, or a bracketed list of them.
+fn parse_patterns(input: ParseStream) -> syn::Result<Vec<String>> {
+    Ok(parse_literals(input)?.iter().map(LitStr::value).collect())
+}
+
+/// A single string literal, or a bracketed list of them.
+fn parse_literals(input: ParseStream) -> syn::Result<Vec<LitStr>> {
+    if input.peek(token::Bracket) {
+        let content;
+        syn::bracketed!(content in input);
+        let literals = Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?;
+        Ok(literals.into_iter().collect())
+    } else {
+        Ok(vec![input.parse::<LitStr>()?])
+    }
+}
+
+/// A string literal holding `what` as `parser` reads it, since it's pasted into the generated code.
+fn parse_code<T>(
+    input: ParseStream,
+    parser: fn(ParseStream) -> syn::Result<T>,
+    what: &str,
+) -> syn::Result<String> {
+    let literal: LitStr = input.parse()?;
+    check_code(&literal, parser, what)?;
+    Ok(literal.value())
+}
+
+/// Like [`parse_code`], for a single string literal or a bracketed list of them.
+fn parse_codes<T>(
+    input: ParseStream,
+    parser: fn(ParseStream) -> syn::Result<T>,
+    what: &str,
+) -> syn::Result<Vec<String>> {
+    let literals = parse_literals(input)?;
+    for literal in &literals {
+        check_code(literal, parser, what)?;
+    }
+    Ok(literals.iter().map(LitStr::value).collect())
+}
+
+/// Fails at `literal` unless it holds `what` as `parser` reads it.
+fn check_code<T>(
+    literal: &LitStr,
+    parser: fn(ParseStream) -> syn::Result<T>,
+    what: &str,
+) -> syn::Result<()> {
+    parser.parse_str(&literal.value()).map(drop).map_err(|_| {
+        syn::Error::new(
+            literal.span(),
+            format!("`{}` is not {}", literal.value(), what),
+        )
+    })
+}
+
+/// `"lexicographic"`, `"input"` or `"discovery"`.
+fn parse_order(input: ParseStream) -> syn::Result<Order> {
+    let order: LitStr = input.parse()?;
+    match order.value().as_str() {
+        "lexicographic" => Ok(Order::Lexicographic),
+        "input" => Ok(Order::Input),
+        "discovery" => Ok(Order::Discovery),
+        _ => Err(syn::Error::new(
+            order.span(),
+            "expected `\"lexicographic\"`, `\"input\"` or `\"discovery\"`",
+        )),
+    }
+}
+
+/// `"allow"`, `"warn"` or `"deny"`.
+fn parse_severity(input: ParseStream) -> syn::Result<Severity> {
+    let severity: LitStr = input.parse()?;
+    match severity.value().as_str() {
+        "allow" => Ok(Severity::Allow),
+        "warn" => Ok(Severity::Warn),
+        "deny" => Ok(Severity::Deny),
+        _ => Err(syn::Error::new(
+            severity.span(),
+            "expected `\"allow\"`, `\"warn\"` or `\"deny\"`",
+        )),
+    }
+}
+
+/// The contents of `group(root = "dir", import_path = "dir", index = "file", patterns...)`.
+fn parse_group(input: ParseStream) -> syn::Result<Group> {
+    let mut root = None;
+    let mut import_paths = Vec::new();
+    let mut indexes = Vec::new();
+    let mut patterns = Vec::new();
+    while !input.is_empty() {
+        if input.peek(LitStr) || input.peek(token::Bracket) {
+            patterns.extend(parse_patterns(input)?);
+        } else {
+            let name: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value = input.parse::<LitStr>()?.value();
+            match name.to_string().as_str() {
+                "root" => root = Some(value),
+                "import_path" => import_paths.push(value),
+                "index" => indexes.push(value),
+                _ => {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("unknown group option `{}`", name),
+                    ))
+                }
+            }
+        }
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+        }
+    }
+
+    let root = root.ok_or_else(|| input.error("expected a `root = \"...\"` for this group"))?;
+    if patterns.is_empty() && indexes.is_empty() {
+        return Err(input.error("expected at least one pattern"));
+    }
+    let mut group = Group::new(root);
+    for pattern in patterns {
+        group.pattern(pattern);
+    }
+    for index in indexes {
+        group.index(index);
+    }
+    for import_path in import_paths {
+        group.import_path(import_path);
+    }
+    Ok(group)
 }
 
 #[cfg(test)]
@@ -105,17 +602,678 @@ mod tests {
     #[test]
     fn basic_file_test() -> anyhow::Result<()> {
         //println!("{:?}", std::env::current_dir().unwrap());
-        let contents = process_inner(["tests/example.capnp"])?.to_string();
-        assert!(contents.starts_with("pub mod example_capnp {"));
-        assert!(contents.ends_with("}"));
+        let contents = process_inner(Config::new().pattern("tests/example.capnp"), None)?;
+        assert!(contents
+            .starts_with("// This file is autogenerated by capnp-import\n#[rustfmt::skip]\npub mod example_capnp {"));
+        assert!(contents.trim_end().ends_with('}'));
         Ok(())
     }
 
     #[test]
     fn glob_test() -> anyhow::Result<()> {
-        let contents = process_inner(["tests/folder-test/*.capnp"])?;
-        let tests_module: syn::ItemMod = syn::parse2(contents)?;
+        let contents = process_inner(Config::new().pattern("tests/folder-test/*.capnp"), None)?;
+        let tests_module: syn::ItemMod = syn::parse_str(&contents)?;
         assert_eq!(tests_module.ident, "foo_capnp");
         Ok(())
     }
+
+    #[test]
+    fn include_test() -> anyhow::Result<()> {
+        let out_dir = tempfile::tempdir()?;
+        let contents = process_inner(
+            Config::new().pattern("tests/example.capnp"),
+            Some(out_dir.path()),
+        )?;
+        let tests_module: syn::ItemMod = syn::parse_str(&contents)?;
+        assert_eq!(tests_module.ident, "example_capnp");
+        let generated = out_dir.path().join("example_capnp.rs");
+        assert!(contents.contains(&format!("include!({:?})", generated.to_string_lossy())));
+        assert!(generated.is_file());
+
+        let contents = process_inner(
+            Config::new()
+                .pattern("tests/example.capnp")
+                .inline_mod(true),
+            Some(out_dir.path()),
+        )?;
+        assert!(!contents.contains("include!"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quoted_path_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        let out_dir = tempfile::tempdir()?;
+        std::fs::create_dir(schemas.path().join("say \"cheese\""))?;
+        std::fs::create_dir(out_dir.path().join("say \"cheese\""))?;
+        std::fs::copy(
+            "tests/example.capnp",
+            schemas.path().join("say \"cheese\"/example.capnp"),
+        )?;
+        let contents = process_inner(
+            Config::new().pattern("**/*.capnp").root(schemas.path()),
+            Some(&out_dir.path().join("say \"cheese\"")),
+        )?;
+        let tests_module: syn::ItemMod = syn::parse_str(&contents)?;
+        assert_eq!(tests_module.ident, "example_capnp");
+        assert!(contents.contains("say \\\"cheese\\\"/example_capnp.rs"));
+        Ok(())
+    }
+
+    #[test]
+    fn incremental_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        let out_dir = tempfile::tempdir()?;
+        let schema = schemas.path().join("example.capnp");
+        let generated = out_dir.path().join("example_capnp.rs");
+        let original = std::fs::read_to_string("tests/example.capnp")?;
+        std::fs::write(&schema, &original)?;
+        let mut config = Config::new();
+        config
+            .pattern("*.capnp")
+            .root(schemas.path())
+            .incremental(true);
+
+        process_inner(&config, Some(out_dir.path()))?;
+        assert!(out_dir.path().join("capnp_import_state").is_file());
+        std::fs::write(&generated, "// left alone")?;
+
+        // rewriting the same contents isn't a change
+        std::fs::write(&schema, &original)?;
+        process_inner(&config, Some(out_dir.path()))?;
+        assert_eq!(std::fs::read_to_string(&generated)?, "// left alone");
+
+        // as if capnp was upgraded since
+        let state_file = out_dir.path().join("capnp_import_state");
+        let state = std::fs::read_to_string(&state_file)?;
+        let (_, hashes) = state.split_once('\n').unwrap();
+        std::fs::write(
+            &state_file,
+            format!("# compiled with capnp 0.0.1\n{}", hashes),
+        )?;
+        process_inner(&config, Some(out_dir.path()))?;
+        assert!(std::fs::read_to_string(&generated)?.contains("pub mod person"));
+
+        // as are options that change the generated code
+        std::fs::write(&generated, "// left alone")?;
+        process_inner(config.capnp_crate("::capnp"), Some(out_dir.path()))?;
+        assert!(std::fs::read_to_string(&generated)?.contains("pub mod person"));
+
+        std::fs::write(&schema, original.clone() + "struct Extra {}\n")?;
+        process_inner(&config, Some(out_dir.path()))?;
+        assert!(std::fs::read_to_string(&generated)?.contains("pub mod extra"));
+
+        // a failed run records nothing, so the schema isn't taken as compiled by the next one
+        let state = std::fs::read_to_string(&state_file)?;
+        std::fs::write(&schema, original + "struct Broken {\n")?;
+        assert!(process_inner(&config, Some(out_dir.path())).is_err());
+        assert_eq!(std::fs::read_to_string(&state_file)?, state);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_files_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("example.capnp"))?;
+        std::fs::write(schemas.path().join("truncated.capnp"), "\n  \n")?;
+        let mut config = Config::new();
+        config.pattern("*.capnp").root(schemas.path());
+
+        let contents = process_inner(&config, None)?;
+        assert!(contents.contains("pub mod example_capnp"));
+        assert!(!contents.contains("truncated_capnp"));
+
+        config.empty_files(Severity::Deny);
+        let error = process_inner(&config, None).unwrap_err().to_string();
+        assert!(error.contains("truncated.capnp"));
+        assert!(error.contains("is empty"));
+        Ok(())
+    }
+
+    #[test]
+    fn strict_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("example.capnp"))?;
+        std::fs::write(
+            schemas.path().join("example.capnproto"),
+            "@0xe7e3a3d1c4b2a190;\nstruct Other {}\n",
+        )?;
+        let mut config = Config::new();
+        config
+            .pattern("*.{capnp,capnproto}")
+            .root(schemas.path())
+            .strict(true);
+        let error = process_inner(&config, None).unwrap_err().to_string();
+        assert!(error.contains("example.capnproto"));
+        assert!(error.contains("`example_capnp`"));
+
+        config.order(Order::Input);
+        let error = process_inner(&config, None).unwrap_err().to_string();
+        assert!(error.contains("are both emitted as the module `example_capnp`"));
+
+        std::fs::remove_file(schemas.path().join("example.capnproto"))?;
+        process_inner(
+            Config::new().pattern("tests/example.capnp").strict(true),
+            None,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn strip_prefix_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        let prefixed = schemas.path().join("com/example/net");
+        std::fs::create_dir_all(&prefixed)?;
+        std::fs::copy("tests/example.capnp", prefixed.join("example.capnp"))?;
+        let mut config = Config::new();
+        config
+            .pattern("**/*.capnp")
+            .root(schemas.path())
+            .nested(true)
+            .strip_prefix("com/example");
+        let contents = process_inner(&config, None)?;
+        let net: syn::ItemMod = syn::parse_str(&contents)?;
+        assert_eq!(net.ident, "net");
+        match &net.content.as_ref().unwrap().1[..] {
+            [syn::Item::Mod(module)] => assert_eq!(module.ident, "example_capnp"),
+            items => panic!("expected a single module, got {} items", items.len()),
+        }
+
+        std::fs::create_dir(schemas.path().join("net"))?;
+        std::fs::write(
+            schemas.path().join("net/example.capnp"),
+            "@0xe7e3a3d1c4b2a191;\nstruct Other {}\n",
+        )?;
+        let error = process_inner(&config, None).unwrap_err().to_string();
+        assert!(error.contains("`net::example_capnp`"));
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_ids_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("first.capnp"))?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("second.capnp"))?;
+        let error = process_inner(
+            Config::new()
+                .pattern("*.capnp")
+                .root(schemas.path())
+                .duplicate_ids(Severity::Deny),
+            None,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("first.capnp"));
+        assert!(error.contains("second.capnp"));
+        assert!(error.contains("@0xdbb9ad1f14bf0b36"));
+        Ok(())
+    }
+
+    #[test]
+    fn lift_docs_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        let example = std::fs::read_to_string("tests/example.capnp")?;
+        std::fs::write(
+            schemas.path().join("example.capnp"),
+            format!("# People and their birthdays.\n{}", example),
+        )?;
+        let contents = process_inner(
+            Config::new()
+                .pattern("*.capnp")
+                .root(schemas.path())
+                .lift_docs(true),
+            None,
+        )?;
+        assert!(contents.contains(
+            "/// People and their birthdays.\n#[rustfmt::skip]\npub mod example_capnp {"
+        ));
+        let tests_module: syn::ItemMod = syn::parse_str(&contents)?;
+        assert!(tests_module.attrs[0].path().is_ident("doc"));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_index_entries_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let index = dir.path().join("schema_index.txt");
+        std::fs::write(
+            &index,
+            "# schemas\ntests/example.capnp\n\ntests/gone.capnp\ntests/also-gone.capnp\n",
+        )?;
+        let error = process_inner(Config::new().index(&index), None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("gone.capnp"));
+        assert!(error.contains("also-gone.capnp"));
+        assert!(!error.contains("example.capnp"));
+        Ok(())
+    }
+
+    #[test]
+    fn capnp_crate_test() -> anyhow::Result<()> {
+        let contents = process_inner(
+            Config::new()
+                .pattern("tests/example.capnp")
+                .capnp_crate("::capnp_renamed"),
+            None,
+        )?;
+        assert!(contents.contains("::capnp_renamed::"));
+        assert!(!contents.contains("::capnp::"));
+        assert!(!contents.contains(" capnp::"));
+        Ok(())
+    }
+
+    #[test]
+    fn formatter_test() -> anyhow::Result<()> {
+        let out_dir = tempfile::tempdir()?;
+        let generated = out_dir.path().join("example_capnp.rs");
+        let mut config = Config::new();
+        config.pattern("tests/example.capnp");
+        process_inner(&config, Some(out_dir.path()))?;
+        let unformatted = std::fs::read_to_string(&generated)?;
+
+        config.formatter("rustfmt --edition 2021");
+        process_inner(&config, Some(out_dir.path()))?;
+        assert_ne!(std::fs::read_to_string(&generated)?, unformatted);
+
+        config
+            .formatter("no-such-formatter")
+            .formatter_failures(Severity::Deny);
+        assert!(process_inner(&config, Some(out_dir.path())).is_err());
+        config.formatter_failures(Severity::Warn);
+        assert!(process_inner(&config, Some(out_dir.path())).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn single_file_test() -> anyhow::Result<()> {
+        let out_dir = tempfile::tempdir()?;
+        let contents = process_inner(
+            Config::new()
+                .pattern("tests/{example,folder-test/foo}.capnp")
+                .no_header()
+                .single_file(true),
+            Some(out_dir.path()),
+        )?;
+        let merged = std::fs::read_dir(out_dir.path())?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .find(|path| path.to_string_lossy().contains("capnp_merged_"))
+            .expect("no merged file was written");
+        assert_eq!(
+            contents,
+            format!(
+                "include!({:?});\n",
+                capnp_import_core::normalize_path(&merged)?
+            )
+        );
+        let merged: syn::File = syn::parse_str(&std::fs::read_to_string(merged)?)?;
+        assert_eq!(merged.items.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn deny_warnings_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let schemas = dir.path().join("schemas");
+        std::fs::create_dir(&schemas)?;
+        std::fs::write(
+            schemas.join("b.capnp"),
+            "@0xe1e2e3e4e5e6e7e8;\nstruct B {}\n",
+        )?;
+        std::fs::write(
+            schemas.join("a.capnp"),
+            "@0xe1e2e3e4e5e6e7e9;\nusing import \"/schemas/b.capnp\".B;\nstruct A { b @0 :B; }\n",
+        )?;
+        // the directory above the root as an import path makes capnp find b.capnp twice
+        let mut group = Group::new(&schemas);
+        group.pattern("*.capnp").import_path(dir.path());
+        let mut config = Config::new();
+        config.group(group).deny_warnings(true);
+        let error = format!("{:#}", process_inner(&config, None).unwrap_err());
+        assert!(error.contains("warning: Found exactly the same source file"));
+        Ok(())
+    }
+
+    #[test]
+    fn verbose_test() -> anyhow::Result<()> {
+        let mut config = Config::new();
+        config.pattern("tests/ui/syntax_error.broken");
+        let error = format!("{:#}", process_inner(&config, None).unwrap_err());
+        assert!(error.contains("syntax_error.broken:5:1: error: Parse error."));
+        assert!(!error.contains("--verbose"));
+
+        config.verbose(true);
+        let error = format!("{:#}", process_inner(&config, None).unwrap_err());
+        assert!(error.contains("\"--verbose\""));
+        assert!(error.contains("Parse error."));
+        Ok(())
+    }
+
+    #[test]
+    fn expose_test() -> anyhow::Result<()> {
+        let mut config = Config::new();
+        config.pattern("tests/example.capnp").expose("Date");
+        let contents = process_inner(&config, None)?;
+        assert!(contents.contains("pub(crate) mod example_capnp {"));
+        assert!(contents.contains("pub use example_capnp::date;"));
+        assert!(!contents.contains("pub use example_capnp::person;"));
+
+        config.expose("PhoneNumber");
+        let error = process_inner(&config, None).unwrap_err().to_string();
+        assert!(error.contains("`PhoneNumber`"));
+        Ok(())
+    }
+
+    #[test]
+    fn reexport_test() -> anyhow::Result<()> {
+        let args: Args = syn::parse_str(
+            "\"tests/example.capnp\", reexport = [\"example_capnp::person as user\", \"example_capnp::date as Day\"]",
+        )?;
+        let contents = process_inner(&args.config, None)?;
+        assert!(contents.contains("pub mod example_capnp {"));
+        assert!(contents.contains("pub(crate) use example_capnp::person as user;\n"));
+        assert!(contents.contains("pub(crate) use example_capnp::date as Day;\n"));
+
+        let contents = process_inner(
+            Config::new()
+                .pattern("tests/example.capnp")
+                .parent("proto")
+                .reexport("example_capnp::person", "Person"),
+            None,
+        )?;
+        assert!(contents.ends_with("}\npub(crate) use proto::example_capnp::person as Person;\n"));
+
+        for (path, name) in [
+            ("example_capnp::person", "fn"),
+            ("example_capnp::person", "a-b"),
+            ("example_capnp::person", "example_capnp"),
+            ("example_capnp::", "person"),
+            ("other_capnp::person", "person"),
+        ] {
+            let mut config = Config::new();
+            config.pattern("tests/example.capnp").reexport(path, name);
+            assert!(
+                process_inner(&config, None).is_err(),
+                "{} as {}",
+                path,
+                name
+            );
+        }
+        let mut config = Config::new();
+        config
+            .pattern("tests/example.capnp")
+            .reexport("example_capnp::person", "Entry")
+            .reexport("example_capnp::date", "Entry");
+        let error = process_inner(&config, None).unwrap_err().to_string();
+        assert!(error.contains("`example_capnp::date` as `Entry`"));
+
+        assert!(syn::parse_str::<Args>("reexport = \"example_capnp::person\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn feature_test() -> anyhow::Result<()> {
+        let contents = process_inner(
+            Config::new()
+                .pattern("tests/example.capnp")
+                .feature("extended"),
+            None,
+        )?;
+        let tests_module: syn::ItemMod = syn::parse_str(&contents)?;
+        assert!(tests_module.attrs[0].path().is_ident("cfg"));
+        assert!(contents
+            .contains("#[cfg(feature = \"extended\")]\n#[rustfmt::skip]\npub mod example_capnp {"));
+        Ok(())
+    }
+
+    #[test]
+    fn code_options_test() -> anyhow::Result<()> {
+        let args: Args = syn::parse_str(
+            "\"tests/example.capnp\", visibility = \"pub(crate)\", allow = [\"clippy::all\", \"dead_code\"], \
+             capnp_crate = \"::capnp\", expose = [\"Date\"]",
+        )?;
+        assert!(process_inner(&args.config, None)?.contains("pub(crate) mod example_capnp {"));
+
+        for (invalid, message) in [
+            (
+                "visibility = \"pub(crate\"",
+                "`pub(crate` is not a visibility",
+            ),
+            (
+                "allow = [\"dead_code\", \"dead code\"]",
+                "`dead code` is not a lint",
+            ),
+            ("capnp_crate = \"::capnp;\"", "`::capnp;` is not a path"),
+            ("expose = \"Date {\"", "`Date {` is not a type name"),
+        ] {
+            let error = syn::parse_str::<Args>(invalid).err().map(|e| e.to_string());
+            assert_eq!(error.as_deref(), Some(message), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cfg_test() -> anyhow::Result<()> {
+        let args: Args = syn::parse_str(
+            "module = \"dev\", cfg = feature = \"dev-schemas\", [\"tests/example.capnp\"]",
+        )?;
+        let contents = process_inner(&args.config, None)?;
+        let dev: syn::ItemMod = syn::parse_str(&contents)?;
+        assert_eq!(dev.ident, "dev");
+        assert!(contents.contains("#[cfg(feature = \"dev-schemas\")]\npub mod dev {"));
+
+        let args: Args = syn::parse_str("\"tests/example.capnp\", cfg = all(unix, not(test))")?;
+        let contents = process_inner(&args.config, None)?;
+        let example: syn::ItemMod = syn::parse_str(&contents)?;
+        let predicate: syn::Meta = example.attrs[0].parse_args()?;
+        assert!(example.attrs[0].path().is_ident("cfg"));
+        assert!(predicate.path().is_ident("all"));
+
+        for invalid in [
+            "cfg = feature = 1",
+            "cfg = either(unix, windows)",
+            "cfg = not(unix, windows)",
+            "cfg = std::unix",
+            "cfg = ",
+        ] {
+            assert!(syn::parse_str::<Args>(invalid).is_err(), "{}", invalid);
+        }
+        let error =
+            syn::parse_str::<Args>("module = \"dev-schemas\", cfg = unix, \"tests/example.capnp\"")
+                .map(|args| process_inner(&args.config, None));
+        assert!(error.is_ok_and(|result| result.is_err()));
+        Ok(())
+    }
+
+    #[test]
+    fn defaults_file_test() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let defaults = dir.path().join(".capnp-import.toml");
+        std::fs::write(
+            &defaults,
+            "exclude = [\"tests/folder-test/*.capnp\"]\nallow = [\"dead_code\"]\nvisibility = \"pub(crate)\"\n",
+        )?;
+        let mut config = Config::new();
+        config
+            .defaults_file(&defaults)?
+            .pattern("tests/example.capnp")
+            .pattern("tests/folder-test/*.capnp");
+        let contents = process_inner(&config, None)?;
+        assert!(contents
+            .contains("#[allow(dead_code)]\n#[rustfmt::skip]\npub(crate) mod example_capnp {"));
+        assert!(!contents.contains("foo_capnp"));
+
+        // a missing file is no error
+        Config::new().defaults_file(dir.path().join("missing.toml"))?;
+
+        std::fs::write(&defaults, "modules = \"everywhere\"\n")?;
+        let error = format!("{:#}", Config::new().defaults_file(&defaults).unwrap_err());
+        assert!(error.contains("invalid `modules`"));
+        assert!(error.contains(".capnp-import.toml"));
+        Ok(())
+    }
+
+    #[test]
+    fn parent_test() -> anyhow::Result<()> {
+        let mut config = Config::new();
+        config.pattern("tests/example.capnp").parent("proto");
+        let contents = process_inner(config.no_header(), None)?;
+        let proto: syn::ItemMod = syn::parse_str(&contents)?;
+        assert_eq!(proto.ident, "proto");
+
+        // both would be proto::example_capnp
+        let schemas = tempfile::tempdir()?;
+        std::fs::create_dir(schemas.path().join("v2"))?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("example.capnp"))?;
+        let copy = std::fs::read_to_string("tests/example.capnp")?
+            .replace("0xdbb9ad1f14bf0b36", "0xdbb9ad1f14bf0b37");
+        std::fs::write(schemas.path().join("v2/example.capnp"), copy)?;
+        let mut config = Config::new();
+        config
+            .pattern("**/*.capnp")
+            .root(schemas.path())
+            .parent("proto");
+        let error = process_inner(&config, None).unwrap_err().to_string();
+        assert!(error.contains("`proto::example_capnp`"));
+
+        config.parent("not a module");
+        assert!(process_inner(&config, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sniff_schemas_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("example.capnp"))?;
+        std::fs::write(
+            schemas.path().join("order.schema"),
+            "# orders\n@0xc9d2b6a3a58e4f1a;\nstruct Order {\n  id @0 :UInt64;\n}\n",
+        )?;
+        std::fs::write(schemas.path().join("README.md"), "# not a schema")?;
+        let args: Args = syn::parse_str(r#""**/*", extensions = ["capnp"], sniff_schemas = true"#)?;
+        let mut config = args.config;
+        config.root(schemas.path());
+        let contents = process_inner(&config, None)?;
+        assert!(contents.contains("pub mod example_capnp {"));
+        assert!(contents.contains("pub mod order_capnp {"));
+        Ok(())
+    }
+
+    #[test]
+    fn extensions_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("example.capnp"))?;
+        std::fs::write(schemas.path().join("README.md"), "# not a schema")?;
+        let args: Args = syn::parse_str(r#""**/*", extensions = ["capnp", "capnproto"]"#)?;
+        let mut config = args.config;
+        config.root(schemas.path());
+        let contents = process_inner(&config, None)?;
+        assert!(contents.contains("pub mod example_capnp {"));
+
+        // without them, the greedy pattern picks up the readme
+        let mut config = Config::new();
+        config.pattern("**/*").root(schemas.path());
+        assert!(process_inner(&config, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn best_effort_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        std::fs::copy("tests/example.capnp", schemas.path().join("example.capnp"))?;
+        std::fs::write(
+            schemas.path().join("broken.capnp"),
+            "@0xd4b1ad2d51dc4f17;\nstruct Broken {\n  half @0 :\n",
+        )?;
+        let mut config = Config::new();
+        config.pattern("*.capnp").root(schemas.path());
+        assert!(process_inner(&config, None).is_err());
+
+        let contents = process_inner(config.best_effort(true), None)?;
+        assert!(contents.contains("pub mod example_capnp {"));
+        assert!(!contents.contains("broken_capnp"));
+        Ok(())
+    }
+
+    #[test]
+    fn substitute_test() -> anyhow::Result<()> {
+        let schemas = tempfile::tempdir()?;
+        std::fs::write(
+            schemas.path().join("release.capnp"),
+            "@0xd4b1ad2d51dc4f18;\nconst name :Text = \"{{NAME}}\";\nconst package :Text = \"{{CARGO_PKG_NAME}}\";\n",
+        )?;
+        let mut args: Args =
+            syn::parse_str("substitute = [\"NAME=orders\"], substitute_env = true, \"*.capnp\"")?;
+        args.config.root(schemas.path());
+        let contents = process_inner(&args.config, None)?;
+        assert!(contents.contains("\"orders\""));
+        // cargo sets this for the test run, so the environment doesn't have to be changed
+        assert!(contents.contains(&format!("{:?}", env!("CARGO_PKG_NAME"))));
+
+        assert!(syn::parse_str::<Args>("substitute = \"NAME\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_test() -> anyhow::Result<()> {
+        let mut config = Config::new();
+        config.pattern("tests/example.capnp").runtime("0.14.11");
+        process_inner(&config, None)?;
+
+        for runtime in ["0.15", "0.140"] {
+            config.runtime(runtime);
+            let error = process_inner(&config, None).unwrap_err().to_string();
+            assert!(error.contains(&format!("not {}", runtime)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_comma_test() {
+        let input = r#"
+            ["a.capnp", "b.capnp",],
+            group(
+                root = "proto",
+                ["**/*.capnp",],
+            ),
+            inline_mod = true,
+        "#;
+        assert!(syn::parse_str::<Args>(input).is_ok());
+        assert!(syn::parse_str::<Args>(r#""a.capnp",,"#).is_err());
+    }
+
+    #[test]
+    fn order_test() -> anyhow::Result<()> {
+        let module_names = |order| -> anyhow::Result<Vec<String>> {
+            let contents = process_inner(
+                Config::new()
+                    .pattern("tests/folder-test/*.capnp")
+                    .pattern("tests/example.capnp")
+                    .order(order),
+                None,
+            )?;
+            let file: syn::File = syn::parse_str(&contents)?;
+            Ok(file
+                .items
+                .iter()
+                .map(|item| match item {
+                    syn::Item::Mod(module) => module.ident.to_string(),
+                    _ => panic!("expected only modules"),
+                })
+                .collect())
+        };
+        assert_eq!(
+            module_names(Order::Lexicographic)?,
+            ["example_capnp", "foo_capnp"]
+        );
+        assert_eq!(module_names(Order::Input)?, ["foo_capnp", "example_capnp"]);
+        assert_eq!(
+            module_names(Order::Discovery)?,
+            ["foo_capnp", "example_capnp"]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,15 @@
+// Has to be top level, the generated code refers to the imported schema as `crate::base_capnp`
+capnp_import::capnp_import!(
+    "fixtures/absolute-import-test/proto/**/*.capnp",
+    absolute_import_root = "fixtures/absolute-import-test/proto",
+);
+
+#[test]
+fn absolute_import_test() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut account = message.init_root::<account_capnp::account::Builder>();
+    account.set_owner("Carol");
+    let mut id: base_capnp::id::Builder = account.reborrow().init_id();
+    id.set_value(7);
+    assert_eq!(account.into_reader().get_id().unwrap().get_value(), 7);
+}
@@ -0,0 +1,29 @@
+// Has to be top level
+capnp_import::capnp_import!(
+    "fixtures/annotation-test/schema/*.capnp",
+    annotations_dir = "fixtures/annotation-test/annotations",
+);
+
+#[test]
+fn annotation_test() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut measurement = message.init_root::<measurement_capnp::measurement::Builder>();
+    measurement.set_distance(42.0);
+    // renamed by $Rust.name
+    measurement.set_elapsed(1.5);
+    assert_eq!(measurement.into_reader().get_elapsed(), 1.5);
+}
+
+#[test]
+fn rename_test() {
+    use renamed_capnp::{record, State};
+
+    // the struct, its field, the enum and its enumerant are all renamed by $Rust.name
+    let mut message = capnp::message::Builder::new_default();
+    let mut record = message.init_root::<record::Builder>();
+    record.set_id(7);
+    record.set_state(State::Archived);
+    let record = record.into_reader();
+    assert_eq!(record.get_id(), 7);
+    assert!(matches!(record.get_state(), Ok(State::Archived)));
+}
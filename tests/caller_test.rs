@@ -0,0 +1,10 @@
+#![allow(unused_imports)]
+use capnp_import::capnp_import;
+
+// Resolved against `tests/`, the directory of this file, rather than the crate root
+capnp_import!("example.capnp", relative_to_caller = true);
+
+#[test]
+fn caller_test() {
+    use example_capnp::{date, person};
+}
@@ -0,0 +1,20 @@
+// Has to be top level, `fixtures/extension-test/shape.capnp` declares `Shape` as its only struct
+capnp_import::capnp_import!("fixtures/extension-test/shape.capnp", codec_helpers = true);
+
+#[test]
+fn codec_test() -> capnp::Result<()> {
+    let mut message = capnp::message::TypedBuilder::<shape_capnp::shape::Owned>::new_default();
+    message.init_root().set_sides(3);
+
+    let mut unpacked = Vec::new();
+    shape_capnp::write_message(&mut unpacked, &message)?;
+    let reader = shape_capnp::read_message(&unpacked[..], Default::default())?;
+    assert_eq!(reader.get()?.get_sides(), 3);
+
+    let mut packed = Vec::new();
+    shape_capnp::write_message_packed(&mut packed, &message)?;
+    assert_ne!(packed, unpacked);
+    let reader = shape_capnp::read_message_packed(&packed[..], Default::default())?;
+    assert_eq!(reader.get()?.get_sides(), 3);
+    Ok(())
+}
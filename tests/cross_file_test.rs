@@ -0,0 +1,28 @@
+use capnp_import_core::{process_inner, Config};
+use std::fs;
+use std::path::Path;
+
+// Generates the code of a schema importing a struct from one in a subdirectory, whose file name isn't an
+// identifier, and builds a binary using both, so the references capnpc writes have to resolve to the
+// modules the helper declares.
+#[test]
+fn cross_file_test() -> anyhow::Result<()> {
+    let helper = process_inner(
+        Config::new()
+            .pattern("**/*.capnp")
+            .root("fixtures/cross-file-test"),
+        None,
+    )?;
+    assert!(helper.contains("pub mod money_v2_capnp {"));
+    assert!(helper.contains("crate::money_v2_capnp::money::"));
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cross-file-test");
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("capnp_include.rs"), helper)?;
+    fs::copy(
+        "fixtures/cross-file-test/invoice.rs",
+        dir.join("invoice.rs"),
+    )?;
+    trybuild::TestCases::new().pass(dir.join("invoice.rs"));
+    Ok(())
+}
@@ -0,0 +1,15 @@
+// Has to be top level, contains `fixtures/dir-test/color.capnp` and `fixtures/dir-test/flat-shapes/*.capnp`
+capnp_import::capnp_import_dir!("fixtures/dir-test");
+
+#[test]
+fn dir_test() {
+    use color_capnp::Color;
+    assert_eq!(Color::Blue as u16, 2);
+
+    let mut message = capnp::message::Builder::new_default();
+    let drawing = message.init_root::<flat_shapes::drawing_capnp::drawing::Builder>();
+    let mut squares = drawing.init_squares(1);
+    let mut square: flat_shapes::square_capnp::square::Builder = squares.reborrow().get(0);
+    square.set_side(2.0);
+    assert_eq!(squares.into_reader().get(0).get_side(), 2.0);
+}
@@ -0,0 +1,19 @@
+use std::path::Path;
+use std::process::Command;
+
+// Documents a crate with the docs-only feature, which needs neither a build nor a run of capnp.
+#[test]
+fn docs_only_test() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/docs-only-test/Cargo.toml");
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("docs-only");
+    let status = Command::new(env!("CARGO"))
+        .arg("doc")
+        .arg("--no-deps")
+        .arg("--manifest-path")
+        .arg(manifest)
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(target_dir.join("doc/docs_only_test/index.html").exists());
+}
@@ -0,0 +1,10 @@
+// Has to be top level, the generated code refers to `example_capnp` from the crate root
+capnp_import::capnp_import!("tests/example.capnp", expose = ["Person"]);
+
+#[test]
+fn expose_test() {
+    let mut message = capnp::message::Builder::new_default();
+    message.init_root::<person::Builder>().set_name("Alice");
+    let reader = message.get_root_as_reader::<person::Reader>().unwrap();
+    assert_eq!(reader.get_name().unwrap(), "Alice");
+}
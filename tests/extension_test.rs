@@ -0,0 +1,10 @@
+#![allow(unused_imports)]
+
+// Has to be top level, contains `fixtures/extension-test/shape.capnp` and `fixtures/extension-test/color.capnproto`
+capnp_import::capnp_import!(["fixtures/extension-test/*.{capnp,capnproto}"]);
+
+#[test]
+fn extension_test() {
+    use color_capnp::Color;
+    use shape_capnp::shape;
+}
@@ -0,0 +1,10 @@
+// Has to be top level
+capnp_import::capnp_import!("tests/folder-test/*.capnp", generated_src = true);
+
+#[test]
+fn generated_src_test() {
+    // the same code the module was compiled from
+    assert!(GENERATED_SRC.contains("pub mod foo {"));
+    assert!(GENERATED_SRC.contains("pub fn get_numbers("));
+    let _: Option<foo_capnp::foo::Reader> = None;
+}
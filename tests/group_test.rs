@@ -0,0 +1,18 @@
+#![allow(unused_imports)]
+
+// Has to be top level, each group resolves its absolute imports against its own root
+capnp_import::capnp_import!(
+    group(root = "fixtures/group-test/proto", ["**/*.capnp"]),
+    group(
+        root = "fixtures/group-test/third_party/proto",
+        ["**/*.capnp"]
+    ),
+);
+
+#[test]
+fn group_test() {
+    use clock_capnp::clock;
+    use common_capnp::timestamp;
+    use event_capnp::event;
+    use timer_capnp::timer;
+}
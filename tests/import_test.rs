@@ -0,0 +1,26 @@
+// Has to be top level, the generated code refers to the imported schema as `crate::address_v2_capnp`
+capnp_import::capnp_import!("fixtures/import-test/*.capnp");
+capnp_import::capnp_import!("fixtures/import-test/contacts/*.capnp");
+
+#[test]
+fn import_test() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut person = message.init_root::<person_capnp::person::Builder>();
+    person.set_name("Alice");
+    let mut address: address_v2_capnp::address::Builder = person.reborrow().init_address();
+    address.set_city("Springfield");
+    let person = person.into_reader();
+    assert_eq!(
+        person.get_address().unwrap().get_city().unwrap(),
+        "Springfield"
+    );
+}
+
+#[test]
+fn relative_import_test() {
+    let mut message = capnp::message::Builder::new_default();
+    let book = message.init_root::<book_capnp::book::Builder>();
+    let mut owner: person_capnp::person::Builder = book.init_owner();
+    owner.set_name("Bob");
+    assert_eq!(owner.into_reader().get_name().unwrap(), "Bob");
+}
@@ -0,0 +1,13 @@
+#![allow(unused_imports)]
+
+// Has to be top level
+capnp_import::capnp_import!(
+    index = "fixtures/index-test/schema_index.txt",
+    order = "input"
+);
+
+#[test]
+fn index_test() {
+    use example_capnp::{date, person};
+    use foo_capnp::foo;
+}
@@ -0,0 +1,9 @@
+#![allow(unused_imports)]
+
+// Has to be top level
+capnp_import::capnp_import!("tests/example.capnp", inline_mod = true);
+
+#[test]
+fn inline_test() {
+    use example_capnp::{date, person};
+}
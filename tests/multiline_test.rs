@@ -0,0 +1,26 @@
+#![allow(unused_imports)]
+
+// Has to be top level. Overlapping patterns only generate each schema once
+capnp_import::capnp_import!(
+    [
+        "tests/example.capnp",
+        "tests/ex*.capnp", // same schema, different spelling
+        "tests/{example,folder-test/foo}.capnp",
+        /* block comments are fine too */
+        "tests/folder-test/foo.capnp",
+        "tests/folder-test/*.capnp",
+        "tests/folder-test/**/*.capnp",
+        "tests/**/foo.capnp",
+        "tests/*/f?o.capnp",
+        "fixtures/std-import-test/reading.capnp",
+        "fixtures/std-import-test/*.capnp",
+    ],
+    order = "input",
+);
+
+#[test]
+fn multiline_test() {
+    use example_capnp::{date, person};
+    use foo_capnp::foo;
+    use reading_capnp::reading;
+}
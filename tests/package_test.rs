@@ -0,0 +1,13 @@
+// Has to be top level
+capnp_import::capnp_import!(
+    "tests/offline-test/schema/*.capnp",
+    package = "capnp-import-core"
+);
+
+#[test]
+fn package_test() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut point = message.init_root::<point_capnp::point::Builder>();
+    point.set_x(3);
+    assert_eq!(point.into_reader().get_x(), 3);
+}
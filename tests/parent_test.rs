@@ -0,0 +1,12 @@
+// Has to be top level, the generated code refers to the modules as `crate::proto::*`
+capnp_import::capnp_import!("fixtures/parent-test/*.capnp", parent = "proto");
+
+#[test]
+fn parent_test() {
+    let mut message = capnp::message::Builder::new_default();
+    let canvas = message.init_root::<proto::canvas_capnp::canvas::Builder>();
+    let mut shapes = canvas.init_shapes(1);
+    let mut shape: proto::shape_capnp::shape::Builder = shapes.reborrow().get(0);
+    shape.set_sides(3);
+    assert_eq!(shapes.into_reader().get(0).get_sides(), 3);
+}
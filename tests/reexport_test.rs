@@ -0,0 +1,19 @@
+// Has to be top level, the generated code refers to `example_capnp` from the crate root
+capnp_import::capnp_import!(
+    "tests/example.capnp",
+    reexport = [
+        "example_capnp::person as user",
+        "example_capnp::date as Day"
+    ]
+);
+
+#[test]
+fn reexport_test() {
+    let mut message = capnp::message::Builder::new_default();
+    message.init_root::<user::Builder>().set_name("Alice");
+    let reader = message
+        .get_root_as_reader::<example_capnp::person::Reader>()
+        .unwrap();
+    assert_eq!(reader.get_name().unwrap(), "Alice");
+    let _: Option<Day::Reader> = None;
+}
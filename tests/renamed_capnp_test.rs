@@ -0,0 +1,19 @@
+use std::path::Path;
+use std::process::Command;
+
+// Builds and runs a crate that depends on capnp under another name.
+#[test]
+fn renamed_capnp_test() {
+    let manifest =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/renamed-capnp-test/Cargo.toml");
+    // shared with target_dir_test, so capnp is only built once
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("custom target dir");
+    let status = Command::new(env!("CARGO"))
+        .arg("run")
+        .arg("--manifest-path")
+        .arg(manifest)
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
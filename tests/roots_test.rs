@@ -0,0 +1,20 @@
+#![allow(unused_imports)]
+
+// Has to be top level, each root resolves the absolute imports of its schemas, and `vendor` overlaps
+// `vendor/proto`, whose schemas are only compiled once
+capnp_import::capnp_import!(
+    roots = [
+        "fixtures/roots-test/proto",
+        "fixtures/roots-test/vendor/proto",
+        "fixtures/roots-test/vendor"
+    ],
+    ["**/*.capnp"]
+);
+
+#[test]
+fn roots_test() {
+    use geo_capnp::point;
+    use money_capnp::money;
+    use order_capnp::order;
+    use units_capnp::meters;
+}
@@ -0,0 +1,8 @@
+// Has to be top level, imports persistent.capnp from the standard include directory
+capnp_import::capnp_import!("fixtures/rpc-import-test/*.capnp");
+
+#[test]
+fn rpc_import_test() {
+    // the client and server of the interface are generated
+    fn _uses(_: vault_capnp::vault::Client, _: &dyn vault_capnp::vault::Server) {}
+}
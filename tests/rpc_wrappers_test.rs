@@ -0,0 +1,26 @@
+// Has to be top level, the wrappers are methods of the generated clients
+capnp_import::capnp_import!("fixtures/rpc-wrappers-test/*.capnp", rpc_wrappers = true);
+
+use capnp::capability::{Promise, Response};
+use store_capnp::store;
+
+#[test]
+fn rpc_wrappers_test() {
+    // the wrappers return the promise of the response, without a closure when there are no params
+    fn _get(store: &store::Client) -> Promise<Response<store::get_results::Owned>, capnp::Error> {
+        store.get_async(|mut params| params.set_key("id"))
+    }
+    fn _ping(store: &store::Client) -> Promise<Response<store::ping_results::Owned>, capnp::Error> {
+        store.ping_async()
+    }
+    fn _list_keys(
+        store: &store::Client,
+    ) -> Promise<Response<store::list_keys_results::Owned>, capnp::Error> {
+        store.list_keys_async(|mut params| params.set_prefix("user/"))
+    }
+    fn _changed(
+        watcher: &store::watcher::Client,
+    ) -> Promise<Response<store::watcher::changed_results::Owned>, capnp::Error> {
+        watcher.changed_async(|mut params| params.set_key("id"))
+    }
+}
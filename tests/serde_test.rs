@@ -0,0 +1,65 @@
+// Has to be top level, the shims refer to the generated types through `super`
+capnp_import::capnp_import!(
+    ["tests/example.capnp", "fixtures/serde-test/inventory.capnp"],
+    serde_shims = true
+);
+
+use example_capnp::serde_shims::{Date, Person, PersonPhoneNumber, PersonPhoneNumberType};
+use inventory_capnp::serde_shims::{Inventory, InventoryKind, Item};
+
+#[test]
+fn serde_test() -> anyhow::Result<()> {
+    let person = Person {
+        name: "Alice".to_owned(),
+        birthdate: Date {
+            year: 1990,
+            month: 4,
+            day: 1,
+        },
+        email: "alice@example.com".to_owned(),
+        phones: vec![PersonPhoneNumber {
+            number: "555-1234".to_owned(),
+            r#type: PersonPhoneNumberType::Mobile,
+        }],
+    };
+    let mut message = capnp::message::Builder::new_default();
+    person.build(message.init_root::<example_capnp::person::Builder>());
+    let read = Person::try_from(message.get_root_as_reader::<example_capnp::person::Reader>()?)?;
+    assert_eq!(read, person);
+
+    let json = serde_json::to_string(&read)?;
+    assert!(json.contains(r#""type":"Mobile""#));
+    assert_eq!(serde_json::from_str::<Person>(&json)?, person);
+    Ok(())
+}
+
+#[test]
+fn serde_lists_test() -> anyhow::Result<()> {
+    let inventory = Inventory {
+        name: "shelf".to_owned(),
+        blob: vec![1, 2, 3],
+        marker: (),
+        tags: vec!["a".to_owned(), "b".to_owned()],
+        grid: vec![vec![1, 2], vec![], vec![3]],
+        kinds: vec![InventoryKind::ExtraLarge, InventoryKind::Small],
+        items: vec![Item {
+            label: "box".to_owned(),
+            children: vec![Item {
+                label: "lid".to_owned(),
+                children: Vec::new(),
+            }],
+        }],
+        self_: true,
+        ratio: 0.5,
+    };
+    let mut message = capnp::message::Builder::new_default();
+    inventory.build(message.init_root::<inventory_capnp::inventory::Builder>());
+    let read =
+        Inventory::try_from(message.get_root_as_reader::<inventory_capnp::inventory::Reader>()?)?;
+    assert_eq!(read, inventory);
+    assert_eq!(
+        serde_json::from_str::<Inventory>(&serde_json::to_string(&read)?)?,
+        inventory
+    );
+    Ok(())
+}
@@ -0,0 +1,11 @@
+// Has to be top level
+capnp_import::capnp_import!("fixtures/std-import-test/*.capnp");
+
+#[test]
+fn std_import_test() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut reading = message.init_root::<reading_capnp::reading::Builder>();
+    reading.set_sensor_id("kitchen");
+    reading.set_celsius(21.5);
+    assert_eq!(reading.into_reader().get_celsius(), 21.5);
+}
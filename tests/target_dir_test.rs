@@ -0,0 +1,20 @@
+use std::path::Path;
+use std::process::Command;
+
+// Builds and runs a crate with a build script, so its modules `include!` their code from an OUT_DIR
+// inside a custom CARGO_TARGET_DIR.
+#[test]
+fn target_dir_test() {
+    let manifest =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/target-dir-test/Cargo.toml");
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("custom target dir");
+    let status = Command::new(env!("CARGO"))
+        .arg("run")
+        .arg("--manifest-path")
+        .arg(manifest)
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(target_dir.join("debug").exists());
+}
@@ -0,0 +1,3 @@
+capnp_import::capnp_import!();
+
+fn main() {}
@@ -0,0 +1,3 @@
+capnp_import::capnp_import!("tests/{example,foo.capnp");
+
+fn main() {}
@@ -0,0 +1,3 @@
+capnp_import::capnp_import!("tests/nonexistent.capnp");
+
+fn main() {}
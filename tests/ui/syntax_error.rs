@@ -0,0 +1,5 @@
+// trybuild compiles this from target/tests/trybuild/capnp-import, the extension keeps the
+// broken schema out of the other tests' globs
+capnp_import::capnp_import!("../../../../tests/ui/syntax_error.broken");
+
+fn main() {}